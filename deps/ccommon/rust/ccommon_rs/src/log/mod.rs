@@ -88,6 +88,7 @@ use std::thread;
 use thread_id;
 use thread_local::CachedThreadLocal;
 use time;
+use util::catch_unwind_or;
 
 
 // TODO(simms): add C-side setup code here.
@@ -579,9 +580,11 @@ impl Handle {
 
 #[no_mangle]
 pub unsafe extern "C" fn log_is_setup_rs(cfgp: *mut Handle) -> bool {
-    ptrs::lift_to_option(cfgp)
-        .map(|p| (*p).is_setup() )
-        .expect("log_is_setup_rs was passed a raw pointer")
+    catch_unwind_or("log_is_setup_rs", false, || {
+        ptrs::lift_to_option(cfgp)
+            .map(|p| (*p).is_setup() )
+            .unwrap_or(false)
+    })
 }
 
 const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
@@ -606,58 +609,65 @@ fn log_setup_safe(config: LogConfig) -> Result<Handle> {
 
 #[no_mangle]
 pub unsafe extern "C" fn log_create_handle_rs(cfgp: *mut bind::log_config_rs) -> *mut Handle {
-    ptrs::null_check(cfgp)                                // make sure our input is good
-        .map_err(|e| e.into())                            // error type bookkeeping
-        .and_then(|c|LogConfig::from_raw(c))              // convert the *mut into a rust struct
-        .and_then(log_setup_safe)                         // register our logger
-        .map(|handle| Box::into_raw(Box::new(handle)))    // convert our handle into a raw pointer
-        .unwrap_or_else(|err| {                           // hand it back to C
-            eprintln!("ERROR log_create_handle: {:#?}", err);
-            ptr::null_mut()                               // unless there was an error, then return NULL
-        })
+    catch_unwind_or("log_create_handle_rs", ptr::null_mut(), || {
+        ptrs::null_check(cfgp)                                // make sure our input is good
+            .map_err(|e| e.into())                            // error type bookkeeping
+            .and_then(|c|LogConfig::from_raw(c))              // convert the *mut into a rust struct
+            .and_then(log_setup_safe)                         // register our logger
+            .map(|handle| Box::into_raw(Box::new(handle)))    // convert our handle into a raw pointer
+            .unwrap_or_else(|err| {                           // hand it back to C
+                eprintln!("ERROR log_create_handle: {:#?}", err);
+                ptr::null_mut()                               // unless there was an error, then return NULL
+            })
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn log_shutdown_rs(ph: *mut Handle, timeout_ms: u32) -> LoggerStatus {
-    let mut handle =
-        match ptrs::lift_to_option(ph) {
-            Some(ph) => Box::from_raw(ph),
-            None => return LoggerStatus::NullPointerError,
-        };
+    catch_unwind_or("log_shutdown_rs", LoggerStatus::OtherFailure, || {
+        let mut handle =
+            match ptrs::lift_to_option(ph) {
+                Some(ph) => Box::from_raw(ph),
+                None => return LoggerStatus::NullPointerError,
+            };
 
-    Handle::shutdown(&mut handle, time::Duration::milliseconds(timeout_ms as i64));
+        Handle::shutdown(&mut handle, time::Duration::milliseconds(timeout_ms as i64));
 
-    LoggerStatus::OK
+        LoggerStatus::OK
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn log_destroy_handle_rs(pph: *mut *mut Handle) {
-    assert!(!pph.is_null());
-    let ph = *pph;
-    drop(Box::from_raw(ph));
-    *pph = ptr::null_mut();
+    catch_unwind_or("log_destroy_handle_rs", (), || {
+        if pph.is_null() || (*pph).is_null() {
+            return;
+        }
+        let ph = *pph;
+        drop(Box::from_raw(ph));
+        *pph = ptr::null_mut();
+    })
 }
 
 // for integration testing with C
 #[doc(hidden)]
 #[no_mangle]
 pub unsafe extern "C" fn log_test_threaded_writes_rs() -> bool {
-    let t1 = thread::spawn(move || {
-        for x in 0..10 {
-            error!("thread 1: {}", x);
-        }
-    });
-
-    let t2 = thread::spawn(move || {
-        for x in 0..10 {
-            warn!("thread 2: {}", x);
-        }
-    });
+    catch_unwind_or("log_test_threaded_writes_rs", false, || {
+        let t1 = thread::spawn(move || {
+            for x in 0..10 {
+                error!("thread 1: {}", x);
+            }
+        });
 
-    t1.join().unwrap();
-    t2.join().unwrap();
+        let t2 = thread::spawn(move || {
+            for x in 0..10 {
+                warn!("thread 2: {}", x);
+            }
+        });
 
-    true
+        t1.join().is_ok() && t2.join().is_ok()
+    })
 }
 
 #[cfg(test)]
@@ -14,9 +14,34 @@
 // limitations under the License.
 
 
+use std::any::Any;
 use std::ffi::CStr;
 use std::fs;
 use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Runs `f`, returning `default` instead if it panics. Unwinding out of an
+/// `extern "C"` function is undefined behaviour, so functions exported to C
+/// should run their bodies through this. `name` is used to report the panic.
+pub fn catch_unwind_or<F, T>(name: &str, default: T, f: F) -> T
+    where F: FnOnce() -> T
+{
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: {} panicked: {}", name, panic_message(&*err));
+            default
+        })
+}
+
+fn panic_message(err: &(dyn Any + Send)) -> &str {
+    if let Some(s) = err.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = err.downcast_ref::<String>() {
+        &s[..]
+    } else {
+        "unknown cause"
+    }
+}
 
 /// Recursively removes files and directories under `path` before removing `path` itself.
 /// Returns 0 on success and -1 on error. `errno` will be set to the cause of the failure.
@@ -13,6 +13,7 @@
 #define CDB_PROCESS_MODULE_NAME "cdb::process"
 
 #define OVERSIZE_ERR_MSG    "oversized value, cannot be stored"
+#define VBUF_ERR_MSG        "value larger than vbuf_size, cannot be served"
 #define DELTA_ERR_MSG       "value is not a number"
 #define OOM_ERR_MSG         "server is out of memory"
 #define CMD_ERR_MSG         "command not supported"
@@ -20,9 +21,16 @@
 
 /* value_buf is a buffer of configurable size that processors can use by
  * rsp->vstr.data = value_buf.data. vstr.data is nulled out in response_reset
- * so the link is broken after each response */
+ * so the link is broken after each response. Values are served straight out
+ * of the cdb file where they can be, only the values of a get that have to
+ * be decompressed are packed into it, so it bounds their total size. */
 static struct bstring value_buf;
 
+/* where each key of a get request was found, see cdb_get_multi. the values
+ * point into cdb_handle or value_buf, both of which outlive the response */
+static struct bstring values[MAX_BATCH_SIZE];
+static cdb_get_status_e statuses[MAX_BATCH_SIZE];
+
 static bool process_init = false;
 static process_metrics_st *process_metrics = NULL;
 
@@ -81,30 +89,50 @@ process_teardown(void)
     process_init = false;
 }
 
-static bool
-_get_key(struct response *rsp, struct bstring *key)
+static void
+_get_key(struct response *rsp, struct bstring *key, struct bstring *val,
+         cdb_get_status_e status)
 {
-    /* this is a slight abuse of the bstring API. we're setting
-     * the data pointer to point to the vbuf that was staticly allocated
-     * and we're setting the len of the buffer to the allocation size. This is
-     * so that we can create a rust slice from this information. */
-    rsp->vstr.data = value_buf.data;
-    rsp->vstr.len = value_buf.len;
-
-    struct bstring *vstr = cdb_get(cdb_handle, key, &(rsp->vstr));
-
-    if (vstr != NULL) {
+    switch (status) {
+    case CDB_GET_OK:
+        rsp->vstr = *val;
         rsp->type = RSP_VALUE;
         rsp->key = *key;
         rsp->flag = 0;
         rsp->vcas = 0;
-        rsp->vstr = *vstr;
 
-        log_verb("found key at %p, location %p", key, vstr);
-        return true;
-    } else {
+        log_verb("found key at %p, value len %"PRIu32, key, rsp->vstr.len);
+        break;
+
+    case CDB_GET_NOT_FOUND:
         log_verb("key at %p not found", key);
-        return false;
+        break;
+
+    case CDB_GET_VALUE_TOO_LARGE:
+        log_warn("value of key %.*s is %"PRIu32" bytes, more than is left of "
+                 "vbuf_size %"PRIu32, key->len, key->data, val->len,
+                 value_buf.len);
+        break;
+
+    default:
+        log_error("cdb lookup of key %.*s failed with status %d", key->len,
+                  key->data, status);
+        break;
+    }
+}
+
+static void
+_error_rsp(struct response *rsp, cdb_get_status_e status)
+{
+    INCR(process_metrics, process_ex);
+    INCR(process_metrics, process_server_ex);
+
+    rsp->type = RSP_SERVER_ERROR;
+    if (status == CDB_GET_VALUE_TOO_LARGE) {
+        INCR(process_metrics, get_oversize);
+        rsp->vstr = str2bstr(VBUF_ERR_MSG);
+    } else {
+        rsp->vstr = str2bstr(OTHER_ERR_MSG);
     }
 }
 
@@ -113,14 +141,32 @@ _process_get(struct response *rsp, struct request *req)
 {
     struct bstring *key;
     struct response *r = rsp;
-    uint32_t i;
+    cdb_get_status_e status;
+    uint32_t i, nkey = array_nelem(req->keys);
 
     INCR(process_metrics, get);
+
+    /* look every key up in one go, so the lookups don't each wait on their
+     * own cache misses. keys are stored one after another in req->keys */
+    if (nkey > 0) {
+        status = cdb_get_multi(cdb_handle, array_first(req->keys), nkey,
+                               &value_buf, values, statuses);
+        if (status != CDB_GET_OK) {
+            INCR(process_metrics, get_ex);
+            _error_rsp(r, status);
+            log_error("cdb lookup of get req %p failed with status %d", req,
+                      status);
+            return;
+        }
+    }
+
     /* use chained responses, move to the next response if key is found. */
-    for (i = 0; i < array_nelem(req->keys); ++i) {
+    for (i = 0; i < nkey; ++i) {
         INCR(process_metrics, get_key);
         key = array_get(req->keys, i);
-        if (_get_key(r, key)) {
+        status = statuses[i];
+        _get_key(r, key, &values[i], status);
+        if (status == CDB_GET_OK) {
             req->nfound++;
             r->cas = false;
             r = STAILQ_NEXT(r, next);
@@ -130,8 +176,15 @@ _process_get(struct response *rsp, struct request *req)
                 return;
             }
             INCR(process_metrics, get_key_hit);
-        } else {
+        } else if (status == CDB_GET_NOT_FOUND) {
             INCR(process_metrics, get_key_miss);
+        } else {
+            /* rather than serve a truncated or garbled value we end the
+             * response with an error, in place of the END line */
+            INCR(process_metrics, get_ex);
+            _error_rsp(r, status);
+            log_verb("get req %p failed on key %"PRIu32, req, i);
+            return;
         }
     }
     r->type = RSP_END;
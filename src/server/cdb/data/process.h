@@ -11,7 +11,7 @@
 
 /*          name         type              default      description */
 #define PROCESS_OPTION(ACTION)                                                                          \
-ACTION( vbuf_size,   OPTION_TYPE_UINT, RSP_VAL_BUF_SIZE, "size in bytes of the value response buffer" )
+ACTION( vbuf_size,   OPTION_TYPE_UINT, RSP_VAL_BUF_SIZE, "size in bytes of the buffer values are decompressed into" )
 
 typedef struct {
     PROCESS_OPTION(OPTION_DECLARE)
@@ -27,6 +27,7 @@ typedef struct {
     ACTION( get_key_hit,       METRIC_COUNTER, "# key hits by get"     )\
     ACTION( get_key_miss,      METRIC_COUNTER, "# key misses by get"   )\
     ACTION( get_ex,            METRIC_COUNTER, "# get errors"          )\
+    ACTION( get_oversize,      METRIC_COUNTER, "# values > vbuf_size"  )\
     ACTION( invalid,           METRIC_COUNTER, "# invalid command"     )
 
 typedef struct {
@@ -86,17 +86,20 @@ setup_cdb_handle(cdb_options_st *opt)
     cdb_setup();
 
     struct cdb_handle_create_config cfg;
-    bstring_init(cfg.path);
+    struct bstring path;
 
-    cfg.load_method = (opt->use_mmap.val.vbool) ? CDB_MMAP : CDB_HEAP;
-
-    bstring_set_cstr(cfg.path, opt->cdb_file_path.val.vstr);
-
-    if (cfg.path == NULL) {
+    if (opt->cdb_file_path.val.vstr == NULL) {
         log_stderr("cdb_file_path option not set, cannot continue");
         exit(EX_CONFIG);
     }
 
+    bstring_init(&path);
+    bstring_set_cstr(&path, opt->cdb_file_path.val.vstr);
+
+    cfg.path = &path;
+    cfg.load_method = (opt->use_mmap.val.vbool) ? CDB_MMAP : CDB_HEAP;
+    cfg.verify_checksum = opt->verify_checksum.val.vbool;
+
     return cdb_handle_create(&cfg);
 }
 
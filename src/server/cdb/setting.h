@@ -16,15 +16,16 @@
 #include <channel/cc_tcp.h>
 #include <stream/cc_sockio.h>
 
-/* option related                                                                                            */
-/*          name            type                default     description                                      */
-#define CDB_OPTION(ACTION)                                                                                    \
-    ACTION( daemonize,      OPTION_TYPE_BOOL,   false,      "daemonize the process"                          )\
-    ACTION( pid_filename,   OPTION_TYPE_STR,    NULL,       "file storing the pid"                           )\
-    ACTION( cdb_file_path,  OPTION_TYPE_STR,    "db.cdb",   "location of the .cdb file"                      )\
-    ACTION( use_mmap,       OPTION_TYPE_BOOL,   false,      "use mmap to load the file, false: use the heap" )\
-    ACTION( dlog_intvl,     OPTION_TYPE_UINT,   500,        "debug log flush interval(ms)"                   )\
-    ACTION( klog_intvl,     OPTION_TYPE_UINT,   100,        "cmd log flush interval(ms)"                     )
+/* option related                                                                                             */
+/*          name             type                default     description                                      */
+#define CDB_OPTION(ACTION)                                                                                     \
+    ACTION( daemonize,       OPTION_TYPE_BOOL,   false,      "daemonize the process"                          )\
+    ACTION( pid_filename,    OPTION_TYPE_STR,    NULL,       "file storing the pid"                           )\
+    ACTION( cdb_file_path,   OPTION_TYPE_STR,    "db.cdb",   "location of the .cdb file"                      )\
+    ACTION( use_mmap,        OPTION_TYPE_BOOL,   false,      "use mmap to load the file, false: use the heap" )\
+    ACTION( verify_checksum, OPTION_TYPE_BOOL,   false,      "refuse to load a file whose checksum is bad"    )\
+    ACTION( dlog_intvl,      OPTION_TYPE_UINT,   500,        "debug log flush interval(ms)"                   )\
+    ACTION( klog_intvl,      OPTION_TYPE_UINT,   100,        "cmd log flush interval(ms)"                     )
 
 typedef struct {
     CDB_OPTION(OPTION_DECLARE)
@@ -2,6 +2,7 @@
 
 #include <cc_bstring.h>
 
+#include <stdbool.h>
 #include <stdint.h>
 
 struct cdb_handle;
@@ -11,9 +12,22 @@ typedef enum cdb_load_method {
     CDB_MMAP = 2,
 } cdb_load_method_e;
 
+typedef enum cdb_get_status {
+    CDB_GET_OK = 0,
+    CDB_GET_NOT_FOUND = 1,
+    CDB_GET_VALUE_TOO_LARGE = 2,
+    CDB_GET_ERROR = 3,
+    CDB_GET_COMPRESSED = 4,
+} cdb_get_status_e;
+
+/* path is only read during cdb_handle_create and stays owned by the caller.
+ * With verify_checksum set, loading fails unless the file has a checksum
+ * trailer that matches its contents.
+ */
 struct cdb_handle_create_config {
     struct bstring    *path;
     cdb_load_method_e load_method;
+    bool              verify_checksum;
 };
 
 
@@ -23,4 +37,35 @@ void cdb_handle_destroy(struct cdb_handle **h);
 void cdb_setup(void);
 void cdb_teardown(void);
 
-struct bstring *cdb_get(struct cdb_handle *h, struct bstring *key, struct bstring *value);
+/* Looks up key and copies its value into value->data, which must point at
+ * value->len bytes of space. On CDB_GET_OK value->len is set to the length of
+ * the value. On CDB_GET_VALUE_TOO_LARGE nothing is copied and value->len is set
+ * to the size the value would need. Values in a compressed file are
+ * decompressed into value->data, and value->len is their decompressed size.
+ */
+cdb_get_status_e cdb_get(struct cdb_handle *h, struct bstring *key, struct bstring *value);
+
+/* Looks up key and points value at its value where it sits in the handle's
+ * memory, without copying it. The value stays valid until the handle is
+ * destroyed, and mustn't be written to. On CDB_GET_COMPRESSED the value was
+ * stored compressed, so there's nothing to point at: value->data is NULL,
+ * value->len is the size it decompresses to, and cdb_get has to be used to
+ * fetch it. value->data is NULL for anything other than CDB_GET_OK.
+ */
+cdb_get_status_e cdb_get_ref(struct cdb_handle *h, struct bstring *key, struct bstring *value);
+
+/* Looks up the nkeys keys in keys at once, which is quicker than calling
+ * cdb_get_ref for each of them, as the cache misses of the lookups overlap.
+ * values[i] and statuses[i] are set to what cdb_get_ref would have set value
+ * to and returned for keys[i], except that values that were stored compressed
+ * are decompressed into buf->data, one after another. Such a value gets
+ * CDB_GET_VALUE_TOO_LARGE if it doesn't fit in what's left of buf, and the
+ * values after it still get the space it would have taken. values[i].data is
+ * NULL for anything other than CDB_GET_OK.
+ *
+ * Returns CDB_GET_ERROR without looking anything up if passed a NULL pointer,
+ * CDB_GET_OK otherwise, in which case each key's outcome is in statuses.
+ */
+cdb_get_status_e cdb_get_multi(struct cdb_handle *h, const struct bstring *keys,
+                               uint32_t nkeys, struct bstring *buf,
+                               struct bstring *values, cdb_get_status_e *statuses);
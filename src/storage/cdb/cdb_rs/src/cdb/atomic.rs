@@ -0,0 +1,134 @@
+//! Building a cdb file in place of another, without anyone watching the path
+//! ever seeing a half-written file.
+//!
+//! `AtomicWriter` writes to a temp file next to the destination, and only
+//! once the index is written and the file synced does it rename it over the
+//! destination and sync the directory. If anything fails along the way, or
+//! the writer is dropped without being finished, the temp file is removed and
+//! the destination is left as it was.
+
+use super::writer::{BuildSummary, Writer, WriterConfig};
+use super::Result;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, NamedTempFile};
+
+pub struct AtomicWriter {
+    writer: Writer<BufWriter<File>>,
+    tmp: NamedTempFile,
+    path: PathBuf,
+}
+
+// the directory 'path' lives in
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+impl AtomicWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<AtomicWriter> {
+        AtomicWriter::with_config(path, &WriterConfig::default())
+    }
+
+    pub fn with_config<P: AsRef<Path>>(path: P, config: &WriterConfig) -> Result<AtomicWriter> {
+        let path = path.as_ref().to_path_buf();
+
+        // the temp file has to be on the same filesystem for the rename to
+        // be atomic, so it goes in the same directory
+        let prefix = match path.file_name() {
+            Some(name) => format!(".{}.", name.to_string_lossy()),
+            None => ".cdb.".to_string(),
+        };
+        let tmp = Builder::new()
+            .prefix(&prefix)
+            .suffix(".tmp")
+            .tempfile_in(parent_dir(&path))?;
+
+        let writer = Writer::with_config(BufWriter::new(tmp.as_file().try_clone()?), config)?;
+
+        Ok(AtomicWriter { writer, tmp, path })
+    }
+
+    /// The path the file will be renamed to by `finish`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writer.put(key, value)
+    }
+
+    pub fn put_with_hint(&mut self, key: &[u8], value: &[u8], hint: u64) -> Result<()> {
+        self.writer.put_with_hint(key, value, hint)
+    }
+
+    /// Finishes the file, syncs it and renames it into place.
+    pub fn finish(self) -> Result<BuildSummary> {
+        let AtomicWriter { writer, tmp, path } = self;
+
+        let summary = writer.finish()?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(&path).map_err(|e| e.error)?;
+
+        // make the rename itself durable
+        File::open(parent_dir(&path))?.sync_all()?;
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdb::Reader;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn dir_entries(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn finish_renames_into_place() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db.cdb");
+        fs::write(&path, b"the old file").unwrap();
+
+        let mut w = AtomicWriter::create(&path).unwrap();
+        w.put(b"abc", b"def").unwrap();
+
+        // nothing changes until the writer is finished
+        assert_eq!(fs::read(&path).unwrap(), b"the old file");
+        assert_eq!(dir_entries(&dir).len(), 2);
+
+        let summary = w.finish().unwrap();
+        assert_eq!(summary.records, 1);
+        assert_eq!(dir_entries(&dir), vec!["db.cdb".to_string()]);
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len() as u64, summary.len);
+        let cdb = Reader::new(&data).unwrap();
+        assert_eq!(cdb.get_ref(b"abc").unwrap().unwrap().v, b"def");
+    }
+
+    #[test]
+    fn dropping_cleans_up() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db.cdb");
+
+        {
+            let mut w = AtomicWriter::create(&path).unwrap();
+            w.put(b"abc", b"def").unwrap();
+        }
+
+        assert!(dir_entries(&dir).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use memmap;
 use std::ops::Deref;
 use super::Result;
@@ -5,10 +6,11 @@ use std::fs::File;
 use std::io::{Cursor, Read};
 use std::path::Path;
 use cdb::MAIN_TABLE_SIZE_BYTES;
+use cdb::trailer;
 
 
 pub enum Backend {
-    Heap(Box<[u8]>),
+    Heap(Bytes),
     MMap(memmap::Mmap),
 }
 
@@ -32,11 +34,11 @@ impl Backend {
         let v = {
             let mut buf  = Vec::with_capacity(MAIN_TABLE_SIZE_BYTES as usize);
             let mut cur = Cursor::new(buf);
-            super::Writer::new(&mut cur)?;
+            super::Writer::new(&mut cur)?.finish()?;
             cur.into_inner()
         };
 
-        Ok(Backend::Heap(v.into_boxed_slice()))
+        Ok(Backend::Heap(Bytes::from(v)))
     }
 
     pub fn load_path(p: &Path) -> Result<Backend> {
@@ -47,7 +49,13 @@ impl Backend {
     pub fn load(mut fp: &File) -> Result<Backend> {
         let mut buffer = Vec::new();
         fp.read_to_end(&mut buffer)?;
-        Ok(Backend::Heap(buffer.into_boxed_slice()))
+        Ok(Backend::Heap(Bytes::from(buffer)))
+    }
+
+    /// Like `load_path`, but fails unless the file carries a checksum trailer
+    /// that matches its contents.
+    pub fn load_path_verified(p: &Path) -> Result<Backend> {
+        Backend::load_path(p).and_then(Backend::verified)
     }
 
     pub fn mmap_path(p: &Path) -> Result<Backend> {
@@ -55,6 +63,18 @@ impl Backend {
         Backend::mmap(&fp)
     }
 
+    /// Like `mmap_path`, but fails unless the file carries a checksum trailer
+    /// that matches its contents. Checking the checksum reads in the whole
+    /// mapping.
+    pub fn mmap_path_verified(p: &Path) -> Result<Backend> {
+        Backend::mmap_path(p).and_then(Backend::verified)
+    }
+
+    fn verified(self) -> Result<Backend> {
+        trailer::verify_checksum(&self)?;
+        Ok(self)
+    }
+
     pub fn mmap(fp: &File) -> Result<Backend> {
         Ok(Backend::MMap(
             unsafe { memmap::MmapOptions::new().map(&fp)? }
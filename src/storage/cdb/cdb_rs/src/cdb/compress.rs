@@ -0,0 +1,437 @@
+//! Values stored compressed, see `WriterConfig::compression`.
+//!
+//! In a file whose trailer has `FLAG_COMPRESSED` set, every value starts with
+//! a tag byte saying how the rest of it is stored:
+//!
+//! ```text
+//! 0: the value as it was put
+//! 1: len: u32, then a zstd frame holding the len byte value
+//! 2: len: u32, then an lz4 block holding the len byte value
+//! 3: len: u32, then a zstd frame compressed against the file's dictionary
+//! ```
+//!
+//! Values too small to be worth compressing, and ones that don't shrink, are
+//! stored as they were put. Keys are never compressed, so lookups compare
+//! them as they're stored.
+//!
+//! A file whose values are compressed against a dictionary carries it after
+//! the secondary tables, where its trailer says.
+
+use super::errors::CDBError;
+use super::Result;
+use bytes::{Buf, BufMut, IntoBuf};
+use failure;
+use libc::{c_char, c_int};
+use lz4;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+use std::result;
+use zstd;
+use zstd_safe::{self, CCtx, CDict, DCtx, DDict};
+
+const TAG_RAW: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+const TAG_LZ4: u8 = 2;
+const TAG_ZSTD_DICT: u8 = 3;
+
+// tag: u8, len: u32
+const HEADER_SIZE: usize = 5;
+
+// lz4 counts in ints, so anything bigger is stored as is
+const MAX_COMPRESSED_LEN: usize = i32::max_value() as usize;
+
+// an lz4 block can't decompress to more than about 255 times its size
+const MAX_LZ4_RATIO: usize = 255;
+
+/// How values are compressed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Values are stored as they were put.
+    None,
+    /// zstd at `level`, where 0 is zstd's default.
+    Zstd { level: i32 },
+    /// lz4, which compresses less than zstd but decompresses faster.
+    Lz4,
+    /// zstd at `level`, against a dictionary of up to `size` bytes that the
+    /// writer trains on the first values put and stores in the file. Suits
+    /// small values that look alike, which gain little compressed on their
+    /// own.
+    ZstdDictionary { level: i32, size: usize },
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+fn zstd_error(code: usize) -> failure::Error {
+    failure::err_msg(format!("zstd: {}", zstd_safe::get_error_name(code)))
+}
+
+/// Turns values into the form they're stored in.
+pub struct Encoder {
+    compression: Compression,
+    min_size: usize,
+    zstd: zstd::block::Compressor,
+    // the dictionary prepared at the compression level, and a context to
+    // compress with it
+    dictionary: Option<(CDict<'static>, CCtx)>,
+}
+
+impl Encoder {
+    /// An encoder that leaves values smaller than `min_size` as they are.
+    /// `Compression::ZstdDictionary` compresses with plain zstd until it's
+    /// given a dictionary, see `Encoder::set_dictionary`.
+    pub fn new(compression: Compression, min_size: usize) -> Encoder {
+        Encoder {
+            compression,
+            min_size,
+            zstd: zstd::block::Compressor::new(),
+            dictionary: None,
+        }
+    }
+
+    /// Compresses values against `dictionary` from now on. Only
+    /// `Compression::ZstdDictionary` uses one.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        if let Compression::ZstdDictionary { level, .. } = self.compression {
+            let cdict = zstd_safe::create_cdict(dictionary, level);
+            self.dictionary = Some((cdict, zstd_safe::create_cctx()));
+        }
+    }
+
+    /// Appends `value` to `out` the way it's stored, returning whether it was
+    /// compressed.
+    pub fn encode(&mut self, value: &[u8], out: &mut Vec<u8>) -> Result<bool> {
+        let compressed = if value.len() < self.min_size || value.len() > MAX_COMPRESSED_LEN {
+            None
+        } else {
+            match self.compression {
+                Compression::None => None,
+                Compression::Zstd { level } => Some((TAG_ZSTD, self.zstd.compress(value, level)?)),
+                Compression::Lz4 => Some((TAG_LZ4, lz4::block::compress(value, None, false)?)),
+                Compression::ZstdDictionary { level, .. } => match self.dictionary {
+                    Some((ref cdict, ref mut cctx)) => {
+                        Some((TAG_ZSTD_DICT, compress_with_dictionary(cctx, cdict, value)?))
+                    }
+                    None => Some((TAG_ZSTD, self.zstd.compress(value, level)?)),
+                },
+            }
+        };
+
+        match compressed {
+            Some((tag, ref buf)) if HEADER_SIZE + buf.len() < 1 + value.len() => {
+                out.reserve(HEADER_SIZE + buf.len());
+                out.put_u8(tag);
+                out.put_u32_le(value.len() as u32);
+                out.extend_from_slice(buf);
+                Ok(true)
+            }
+            _ => {
+                out.reserve(1 + value.len());
+                out.put_u8(TAG_RAW);
+                out.extend_from_slice(value);
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Trains a dictionary of up to `size` bytes on `samples`. Returns None when
+/// zstd can't make one out of them, usually because there are too few.
+pub fn train<S: AsRef<[u8]>>(samples: &[S], size: usize) -> Option<Vec<u8>> {
+    zstd::dict::from_samples(samples, size).ok()
+}
+
+fn compress_with_dictionary(cctx: &mut CCtx, cdict: &CDict, value: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; zstd_safe::compress_bound(value.len())];
+    let n = zstd_safe::compress_using_cdict(cctx, &mut buf, value, cdict).map_err(zstd_error)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// A file's dictionary, prepared once up front for decompressing values.
+pub struct Dictionary(DDict<'static>);
+
+impl Dictionary {
+    pub fn new(bytes: &[u8]) -> Dictionary {
+        Dictionary(zstd_safe::create_ddict(bytes))
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Dictionary")
+    }
+}
+
+/// The dictionary a file's values were compressed against, either as it's
+/// stored in the file or prepared up front, which saves preparing it again
+/// for every value.
+#[derive(Copy, Clone, Debug)]
+pub enum DictionaryRef<'a> {
+    Stored(&'a [u8]),
+    Prepared(&'a Dictionary),
+}
+
+thread_local! {
+    // setting up a context for every value would cost more than decompressing
+    // most of them
+    static DCTX: RefCell<DCtx> = RefCell::new(zstd_safe::create_dctx());
+}
+
+fn zstd_corrupt(code: usize) -> CDBError {
+    CDBError::CorruptValue { reason: format!("zstd: {}", zstd_safe::get_error_name(code)) }
+}
+
+// splits a stored value into its tag, the length of the value it holds, and
+// its payload
+fn parse(stored: &[u8]) -> result::Result<(u8, usize, &[u8]), CDBError> {
+    let tag = match stored.first() {
+        Some(&tag) => tag,
+        None => return Err(CDBError::CorruptValue { reason: "missing codec tag".to_string() }),
+    };
+
+    match tag {
+        TAG_RAW => Ok((tag, stored.len() - 1, &stored[1..])),
+        TAG_ZSTD | TAG_LZ4 | TAG_ZSTD_DICT if stored.len() >= HEADER_SIZE => {
+            let len = stored[1..HEADER_SIZE].into_buf().get_u32_le() as usize;
+            Ok((tag, len, &stored[HEADER_SIZE..]))
+        }
+        TAG_ZSTD | TAG_LZ4 | TAG_ZSTD_DICT => {
+            Err(CDBError::CorruptValue { reason: "truncated length".to_string() })
+        }
+        tag => Err(CDBError::UnknownCodec { tag }),
+    }
+}
+
+// checks that 'payload' can hold a value of 'len' bytes, so a damaged length
+// isn't trusted with an allocation
+fn check_len(tag: u8, len: usize, payload: &[u8]) -> result::Result<(), CDBError> {
+    let plausible = match tag {
+        TAG_ZSTD | TAG_ZSTD_DICT => zstd_safe::get_frame_content_size(payload) == len as u64,
+        TAG_LZ4 => len <= payload.len().saturating_mul(MAX_LZ4_RATIO),
+        _ => true,
+    };
+    if plausible {
+        Ok(())
+    } else {
+        Err(CDBError::CorruptValue {
+            reason: format!("{} bytes can't decompress to {}", payload.len(), len),
+        })
+    }
+}
+
+/// Whether the stored value `stored` was compressed.
+pub fn is_compressed(stored: &[u8]) -> bool {
+    stored.first().map(|&tag| tag != TAG_RAW).unwrap_or(false)
+}
+
+/// The length of the value held by `stored`.
+pub fn decoded_len(stored: &[u8]) -> result::Result<usize, CDBError> {
+    parse(stored).map(|(_, len, _)| len)
+}
+
+/// Decodes `stored` into `buf`, returning the length of the value. If the
+/// value doesn't fit, `CDBError::ValueTooLarge` is returned with the real size
+/// of the value and `buf` is left untouched. `dictionary` is the file's
+/// dictionary, if it has one.
+pub fn decode_into(
+    stored: &[u8],
+    buf: &mut [u8],
+    dictionary: Option<DictionaryRef>,
+) -> result::Result<usize, CDBError> {
+    let (tag, len, payload) = parse(stored)?;
+    if len > buf.len() {
+        return Err(CDBError::value_too_large(buf.len(), len));
+    }
+    let buf = &mut buf[..len];
+
+    let decoded = match tag {
+        TAG_RAW => {
+            buf.copy_from_slice(payload);
+            len
+        }
+        TAG_ZSTD => DCTX
+            .with(|dctx| zstd_safe::decompress_dctx(&mut dctx.borrow_mut(), buf, payload))
+            .map_err(zstd_corrupt)?,
+        TAG_ZSTD_DICT => {
+            let dictionary = dictionary.ok_or_else(|| CDBError::CorruptValue {
+                reason: "compressed against a dictionary the file doesn't have".to_string(),
+            })?;
+            DCTX.with(|dctx| {
+                let dctx = &mut dctx.borrow_mut();
+                match dictionary {
+                    DictionaryRef::Stored(d) => {
+                        zstd_safe::decompress_using_dict(dctx, buf, payload, d)
+                    }
+                    DictionaryRef::Prepared(d) => {
+                        zstd_safe::decompress_using_ddict(dctx, buf, payload, &d.0)
+                    }
+                }
+            }).map_err(zstd_corrupt)?
+        }
+        _ => {
+            if payload.len() > MAX_COMPRESSED_LEN {
+                return Err(CDBError::CorruptValue { reason: "lz4 block too long".to_string() });
+            }
+            let n = unsafe {
+                lz4::liblz4::LZ4_decompress_safe(
+                    payload.as_ptr() as *const c_char,
+                    buf.as_mut_ptr() as *mut c_char,
+                    payload.len() as c_int,
+                    len as c_int,
+                )
+            };
+            if n < 0 {
+                return Err(CDBError::CorruptValue { reason: "bad lz4 block".to_string() });
+            }
+            n as usize
+        }
+    };
+
+    if decoded != len {
+        return Err(CDBError::CorruptValue {
+            reason: format!("decompressed to {} bytes, expected {}", decoded, len),
+        });
+    }
+    Ok(len)
+}
+
+/// Decodes `stored`, borrowing the value if it was stored as is.
+pub fn decode<'a>(
+    stored: &'a [u8],
+    dictionary: Option<DictionaryRef>,
+) -> result::Result<Cow<'a, [u8]>, CDBError> {
+    let (tag, len, payload) = parse(stored)?;
+    if tag == TAG_RAW {
+        return Ok(Cow::Borrowed(payload));
+    }
+
+    check_len(tag, len, payload)?;
+    let mut buf = vec![0u8; len];
+    decode_into(stored, &mut buf, dictionary)?;
+    Ok(Cow::Owned(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressible() -> Vec<u8> {
+        b"{\"name\": \"pink\", \"color\": \"red\"}, ".iter().cloned().cycle().take(1000).collect()
+    }
+
+    fn encode(compression: Compression, min_size: usize, value: &[u8]) -> (bool, Vec<u8>) {
+        let mut out = Vec::new();
+        let compressed = Encoder::new(compression, min_size).encode(value, &mut out).unwrap();
+        (compressed, out)
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = compressible();
+        for &compression in &[Compression::None, Compression::Zstd { level: 3 }, Compression::Lz4] {
+            let (compressed, stored) = encode(compression, 16, &value);
+            assert_eq!(compressed, compression != Compression::None);
+            assert_eq!(is_compressed(&stored), compressed);
+            if compressed {
+                assert!(stored.len() < value.len() / 3);
+            }
+
+            assert_eq!(decoded_len(&stored).unwrap(), value.len());
+            assert_eq!(&decode(&stored, None).unwrap()[..], &value[..]);
+
+            let mut buf = vec![0u8; 2000];
+            assert_eq!(decode_into(&stored, &mut buf, None).unwrap(), value.len());
+            assert_eq!(&buf[..value.len()], &value[..]);
+        }
+    }
+
+    #[test]
+    fn small_and_incompressible_values_are_stored_as_is() {
+        let value = compressible();
+        let (compressed, stored) = encode(Compression::Lz4, 2000, &value);
+        assert!(!compressed);
+        assert_eq!(stored[0], TAG_RAW);
+        assert_eq!(&stored[1..], &value[..]);
+
+        let value: Vec<u8> = (0..64u8).collect();
+        let (compressed, stored) = encode(Compression::Zstd { level: 0 }, 0, &value);
+        assert!(!compressed);
+        assert!(match decode(&stored, None).unwrap() {
+            Cow::Borrowed(v) => v == &value[..],
+            Cow::Owned(_) => false,
+        });
+    }
+
+    #[test]
+    fn decoding_into_a_short_buffer_reports_the_size() {
+        let value = compressible();
+        let (_, stored) = encode(Compression::Zstd { level: 3 }, 0, &value);
+
+        let mut buf = vec![0u8; 999];
+        match decode_into(&stored, &mut buf, None) {
+            Err(CDBError::ValueTooLarge { max_size, val_size }) => {
+                assert_eq!((max_size, val_size), (999, 1000));
+            }
+            other => panic!("expected ValueTooLarge, got {:?}", other),
+        }
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn dictionary_round_trip() {
+        let samples: Vec<Vec<u8>> = (0..2000)
+            .map(|n| format!(r#"{{"id": {}, "name": "user{}", "color": "red"}}"#, n, n * 7))
+            .map(String::into_bytes)
+            .collect();
+        let dict = train(&samples, 4096).unwrap();
+        assert!(dict.len() <= 4096);
+        assert!(train(&samples[..2], 4096).is_none());
+
+        let mut encoder = Encoder::new(Compression::ZstdDictionary { level: 3, size: 4096 }, 0);
+        encoder.set_dictionary(&dict);
+        let prepared = Dictionary::new(&dict);
+        let value = &samples[1234];
+        let mut stored = Vec::new();
+        assert!(encoder.encode(value, &mut stored).unwrap());
+        assert_eq!(stored[0], TAG_ZSTD_DICT);
+        assert!(stored.len() < value.len() * 3 / 4);
+
+        let refs = [DictionaryRef::Stored(&dict), DictionaryRef::Prepared(&prepared)];
+        for &d in &refs {
+            assert_eq!(&decode(&stored, Some(d)).unwrap()[..], &value[..]);
+        }
+        assert!(decode(&stored, None).is_err());
+    }
+
+    #[test]
+    fn corrupt_values_are_errors() {
+        let value = compressible();
+        let (_, mut stored) = encode(Compression::Lz4, 0, &value);
+        stored.truncate(20);
+
+        let mut buf = vec![0u8; 1000];
+        assert!(decode_into(&stored, &mut buf, None).is_err());
+        assert!(decode(&[], None).is_err());
+        assert!(decode(&[TAG_ZSTD, 1], None).is_err());
+
+        // a length the payload can't hold is rejected before it's allocated
+        for &compression in &[Compression::Zstd { level: 3 }, Compression::Lz4] {
+            let (_, mut stored) = encode(compression, 0, &value);
+            stored[1..HEADER_SIZE].copy_from_slice(&[0xff; 4]);
+            match decode(&stored, None) {
+                Err(CDBError::CorruptValue { .. }) => (),
+                other => panic!("expected CorruptValue, got {:?}", other),
+            }
+        }
+
+        match decode(&[9, 1, 2, 3], None) {
+            Err(CDBError::UnknownCodec { tag }) => assert_eq!(tag, 9),
+            other => panic!("expected UnknownCodec, got {:?}", other),
+        }
+    }
+}
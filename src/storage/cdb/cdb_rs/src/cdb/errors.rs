@@ -1,6 +1,7 @@
 
 // for docs on the 'failure' crate see https://boats.gitlab.io/failure/intro.html
 
+use std::ascii;
 use std::ops::Range;
 
 #[derive(Debug, Fail)]
@@ -12,11 +13,114 @@ pub enum CDBError {
         display = "pointer {:?} out of valid range {:?} for data segment",
         ptr_val, valid_range
     )]
-    IndexOutOfDataSegment{valid_range: Range<usize>, ptr_val: usize}
+    IndexOutOfDataSegment{valid_range: Range<usize>, ptr_val: usize},
+
+    #[fail(display = "file is {} bytes, too short to hold the {} byte main table", len, min_len)]
+    MainTableTruncated{len: usize, min_len: usize},
+
+    #[fail(
+        display = "secondary table {:?} out of valid range {:?} for the index",
+        table, valid_range
+    )]
+    TableOutOfIndex{valid_range: Range<usize>, table: Range<usize>},
+
+    #[fail(
+        display = "record {:?} runs past the end of the data segment {:?}",
+        record, valid_range
+    )]
+    RecordOverrun{valid_range: Range<usize>, record: Range<usize>},
+
+    #[fail(
+        display = "index entry hash {:#010x} doesn't match the hash of its key {:#010x}",
+        indexed, actual
+    )]
+    HashMismatch{indexed: u32, actual: u32},
+
+    #[fail(
+        display = "index entry at bucket {}, slot {} can't be reached from its home, bucket {}, slot {}",
+        bucket, slot, home_bucket, home_slot
+    )]
+    MisplacedIndexEntry{bucket: usize, slot: usize, home_bucket: usize, home_slot: usize},
+
+    #[fail(display = "trailer has unsupported version {}", version)]
+    UnsupportedTrailerVersion{version: u32},
+
+    #[fail(display = "trailer length {} doesn't fit in a {} byte file", len, file_len)]
+    TrailerOutOfRange{len: usize, file_len: usize},
+
+    #[fail(display = "file has no checksum to verify")]
+    MissingChecksum,
+
+    #[fail(
+        display = "checksum mismatch, trailer says {:#018x} but file hashes to {:#018x}",
+        expected, actual
+    )]
+    ChecksumMismatch{expected: u64, actual: u64},
+
+    #[fail(display = "trailer has unsupported pointer width {}", width)]
+    UnsupportedPointerWidth{width: u8},
+
+    #[fail(
+        display = "file would grow to {} bytes, past the {} bytes its pointers can address",
+        size, max_size
+    )]
+    FileTooLarge{max_size: u64, size: u64},
+
+    #[fail(display = "trailer names unsupported hash function {}", id)]
+    UnsupportedHashFn{id: u8},
+
+    #[fail(display = "file uses features this version doesn't support, flags: {:#010x}", flags)]
+    UnsupportedFeatures{flags: u32},
+
+    #[fail(display = "unsupported main table of 2^{} buckets", bits)]
+    UnsupportedMainTable{bits: u8},
+
+    #[fail(display = "load factor {} isn't above 0 and at most 1", load_factor)]
+    InvalidLoadFactor{load_factor: f64},
+
+    #[fail(display = "false positive rate {} isn't between 0 and 1", rate)]
+    InvalidFalsePositiveRate{rate: f64},
+
+    #[fail(display = "dictionary {:?} out of valid range {:?}", dictionary, valid_range)]
+    DictionaryOutOfRange{valid_range: Range<usize>, dictionary: Range<usize>},
+
+    #[fail(display = "filter {:?} out of valid range {:?}", filter, valid_range)]
+    FilterOutOfRange{valid_range: Range<usize>, filter: Range<usize>},
+
+    #[fail(display = "filter is corrupt: {}", reason)]
+    CorruptFilter{reason: String},
+
+    #[fail(display = "value has unknown codec tag {}", tag)]
+    UnknownCodec{tag: u8},
+
+    #[fail(display = "compressed value is corrupt: {}", reason)]
+    CorruptValue{reason: String},
+
+    #[fail(display = "duplicate key \"{}\"", key)]
+    DuplicateKey{key: String},
+
+    #[fail(display = "couldn't read the file: {}", reason)]
+    Unreadable{reason: String},
 }
 
 impl CDBError {
     pub fn value_too_large(max_size: usize, val_size: usize) -> CDBError {
         CDBError::ValueTooLarge{max_size, val_size}
     }
+
+    pub fn duplicate_key(key: &[u8]) -> CDBError {
+        CDBError::DuplicateKey{key: escape(key)}
+    }
+
+    pub fn filter_misses_key(key: &[u8]) -> CDBError {
+        let reason = format!("key \"{}\" isn't in it", escape(key));
+        CDBError::CorruptFilter{reason}
+    }
+}
+
+fn escape(key: &[u8]) -> String {
+    key.iter()
+        .flat_map(|&b| ascii::escape_default(b))
+        .map(char::from)
+        .collect()
 }
@@ -1,43 +1,32 @@
 use cc_binding as bind;
 use ccommon_rs::bstring::BStr;
-use cdb::{cdb_handle, Reader, Result};
-use cdb;
+use ccommon_rs::util::catch_unwind_or;
+use cdb::{cdb_handle, compress, CDBError, CDBHandleConfig, Reader, Result};
 use env_logger; // TODO: switch to cc_log_rs
-use std::convert::From;
-use std::ffi::CStr;
-use std::os::raw::c_char;
 use std::ptr;
+use std::slice;
 
 pub(in super) mod gen;
 
 
-fn mk_cdb_handler(path: &str) -> Result<cdb_handle> {
-    assert!(
-        !path.is_empty(),
-        "cdb file path was empty, misconfiguration?"
-    );
-    debug!("mk_cdb_handler, path: {:?}", path);
-    let inner = cdb::load_bytes_at_path(path)?;
-
-    Ok(cdb_handle::new(inner))
-}
-
-fn cstr_to_string(s: *const c_char) -> Result<String> {
-    let ps = unsafe { CStr::from_ptr(s) }.to_str()?;
-    Ok(String::from(ps))
-}
-
 #[no_mangle]
-pub extern "C" fn cdb_handle_create(path: *const c_char) -> *mut cdb_handle {
-    assert!(!path.is_null());
-
-    match cstr_to_string(path).and_then(|s| mk_cdb_handler(&s)) {
-        Ok(bhandle) => Box::into_raw(Box::new(bhandle)),
-        Err(err) => {
-            error!("failed to create cdb_handle: {:?}", err);
-            ptr::null_mut()
+pub unsafe extern "C" fn cdb_handle_create(
+    cfg: *const gen::cdb_handle_create_config,
+) -> *mut cdb_handle {
+    catch_unwind_or("cdb_handle_create", ptr::null_mut(), || {
+        let res = CDBHandleConfig::from_raw(cfg).and_then(|cfg| {
+            debug!("cdb_handle_create, path: {:?}", cfg.path());
+            cfg.into_cdb_handle()
+        });
+
+        match res {
+            Ok(bhandle) => Box::into_raw(Box::new(bhandle)),
+            Err(err) => {
+                error!("failed to create cdb_handle: {:?}", err);
+                ptr::null_mut()
+            }
         }
-    }
+    })
 }
 
 #[no_mangle]
@@ -45,51 +34,214 @@ pub unsafe extern "C" fn cdb_get(
     h: *mut cdb_handle,
     k: *const bind::bstring,
     v: *mut bind::bstring,
-) -> *mut bind::bstring {
-    assert!(!h.is_null());
-    assert!(!k.is_null());
-    assert!(!v.is_null());
+) -> gen::cdb_get_status {
+    catch_unwind_or("cdb_get", gen::cdb_get_status_CDB_GET_ERROR, || get(h, k, v))
+}
+
+unsafe fn get(
+    h: *mut cdb_handle,
+    k: *const bind::bstring,
+    v: *mut bind::bstring,
+) -> gen::cdb_get_status {
+    if h.is_null() || k.is_null() || v.is_null() {
+        error!("cdb_get was passed a NULL pointer, h: {:?}, k: {:?}, v: {:?}", h, k, v);
+        return gen::cdb_get_status_CDB_GET_ERROR;
+    }
 
-    // TODO: don't do unwrap, be safe
     let handle = cdb_handle::from_raw(h);
     let key = BStr::from_ptr(k as *mut _);
     let mut val = BStr::from_ptr_mut(v);
 
-    match Reader::from(handle).get(&key, &mut val)  {
+    let res = Reader::from(handle).get(&key, &mut val);
+
+    // this provides access to the underlying struct fields so we can set
+    // .len to the actual number of bytes in the value.
+    status("cdb_get", res, val.as_mut())
+}
+
+// the status of a lookup that copied its value into 'vstr', setting .len to
+// the number of bytes in the value, or the number it needed
+fn status(
+    name: &str,
+    res: Result<Option<usize>>,
+    vstr: &mut bind::bstring,
+) -> gen::cdb_get_status {
+    match res {
         Ok(Some(n)) => {
-            {
-                // this provides access to the underlying struct fields
-                // so we can modify the .len to be the actual number of bytes
-                // in the value.
-                let mut vstr = val.as_mut();
+            vstr.len = n as u32;
+            gen::cdb_get_status_CDB_GET_OK
+        },
+        Ok(None) => gen::cdb_get_status_CDB_GET_NOT_FOUND,
+        Err(err) => match err.downcast_ref::<CDBError>() {
+            Some(CDBError::ValueTooLarge { val_size, .. }) => {
+                vstr.len = *val_size as u32;
+                gen::cdb_get_status_CDB_GET_VALUE_TOO_LARGE
+            },
+            _ => {
+                error!("{} failed: {:?}", name, err);
+                gen::cdb_get_status_CDB_GET_ERROR
+            }
+        },
+    }
+}
+
+// points 'vstr' at 'value', which lives as long as the handle it came from
+fn borrowed(value: &[u8], vstr: &mut bind::bstring) -> gen::cdb_get_status {
+    if value.len() > u32::max_value() as usize {
+        vstr.len = u32::max_value();
+        return gen::cdb_get_status_CDB_GET_VALUE_TOO_LARGE;
+    }
+    vstr.data = value.as_ptr() as *mut _;
+    vstr.len = value.len() as u32;
+    gen::cdb_get_status_CDB_GET_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn cdb_get_ref(
+    h: *mut cdb_handle,
+    k: *const bind::bstring,
+    v: *mut bind::bstring,
+) -> gen::cdb_get_status {
+    catch_unwind_or("cdb_get_ref", gen::cdb_get_status_CDB_GET_ERROR, || get_ref(h, k, v))
+}
+
+unsafe fn get_ref(
+    h: *mut cdb_handle,
+    k: *const bind::bstring,
+    v: *mut bind::bstring,
+) -> gen::cdb_get_status {
+    if h.is_null() || k.is_null() || v.is_null() {
+        error!("cdb_get_ref was passed a NULL pointer, h: {:?}, k: {:?}, v: {:?}", h, k, v);
+        return gen::cdb_get_status_CDB_GET_ERROR;
+    }
+
+    let reader = Reader::from(cdb_handle::from_raw(h));
+    let key = BStr::from_ptr(k as *mut _);
+    let vstr = &mut *v;
+    vstr.data = ptr::null_mut();
+    vstr.len = 0;
+
+    let res = reader.get_ref(&key).and_then(|kv| match kv {
+        Some(kv) => reader.value_ref(kv.v).map(|v| Some((kv.v, v))),
+        None => Ok(None),
+    });
+
+    match res {
+        Ok(Some((_, Some(value)))) => borrowed(value, vstr),
+        Ok(Some((stored, None))) => match compress::decoded_len(stored) {
+            Ok(n) => {
                 vstr.len = n as u32;
+                gen::cdb_get_status_CDB_GET_COMPRESSED
             }
-            val.as_ptr()
+            Err(err) => status("cdb_get_ref", Err(err.into()), vstr),
         },
-        Ok(None) => ptr::null_mut(), // not found, return a NULL
-        Err(err) => {
-            eprintln!("got error: {:?}", err);
-            ptr::null_mut()
-        }
+        res => status("cdb_get_ref", res.map(|_| None), vstr),
     }
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn cdb_get_multi(
+    h: *mut cdb_handle,
+    keys: *const bind::bstring,
+    nkeys: u32,
+    buf: *mut bind::bstring,
+    values: *mut bind::bstring,
+    statuses: *mut gen::cdb_get_status,
+) -> gen::cdb_get_status {
+    catch_unwind_or("cdb_get_multi", gen::cdb_get_status_CDB_GET_ERROR, || {
+        get_multi(h, keys, nkeys, buf, values, statuses)
+    })
+}
+
+unsafe fn get_multi(
+    h: *mut cdb_handle,
+    keys: *const bind::bstring,
+    nkeys: u32,
+    buf: *mut bind::bstring,
+    values: *mut bind::bstring,
+    statuses: *mut gen::cdb_get_status,
+) -> gen::cdb_get_status {
+    if h.is_null() || buf.is_null() {
+        error!("cdb_get_multi was passed a NULL pointer, h: {:?}, buf: {:?}", h, buf);
+        return gen::cdb_get_status_CDB_GET_ERROR;
+    }
+    if nkeys == 0 {
+        return gen::cdb_get_status_CDB_GET_OK;
+    }
+    if keys.is_null() || values.is_null() || statuses.is_null() {
+        error!(
+            "cdb_get_multi was passed a NULL pointer, keys: {:?}, values: {:?}, statuses: {:?}",
+            keys, values, statuses
+        );
+        return gen::cdb_get_status_CDB_GET_ERROR;
+    }
+
+    let n = nkeys as usize;
+    let keys: Vec<&[u8]> = slice::from_raw_parts(keys, n)
+        .iter()
+        .map(|k| &BStr::from_ref(k)[..])
+        .collect();
+    let values = slice::from_raw_parts_mut(values, n);
+    let statuses = slice::from_raw_parts_mut(statuses, n);
+    let buf = &mut BStr::from_ptr_mut(buf)[..];
+
+    let reader = Reader::from(cdb_handle::from_raw(h));
+    // values are lent out of the handle where they can be, the ones that have
+    // to be decompressed go into 'buf' one after another
+    let mut used = 0;
+    for (i, res) in reader.get_many(&keys).into_iter().enumerate() {
+        let res = res.and_then(|kv| match kv {
+            Some(kv) => reader.value_ref(kv.v).map(|v| Some((kv.v, v))),
+            None => Ok(None),
+        });
+
+        values[i] = bind::bstring { len: 0, data: ptr::null_mut() };
+        statuses[i] = match res {
+            Ok(Some((_, Some(value)))) => borrowed(value, &mut values[i]),
+            Ok(Some((stored, None))) => {
+                let space = &mut buf[used..];
+                let res = reader.copy_value(stored, space).map(Some);
+                let status = status("cdb_get_multi", res, &mut values[i]);
+                if status == gen::cdb_get_status_CDB_GET_OK {
+                    values[i].data = space.as_mut_ptr() as *mut _;
+                    used += values[i].len as usize;
+                }
+                status
+            }
+            res => status("cdb_get_multi", res.map(|_| None), &mut values[i]),
+        };
+    }
+
+    gen::cdb_get_status_CDB_GET_OK
+}
+
 
 #[no_mangle]
 pub unsafe extern "C" fn cdb_handle_destroy(handle: *mut *mut cdb_handle) {
-    drop(Box::from_raw(*handle));
-    *handle = ptr::null_mut()
+    catch_unwind_or("cdb_handle_destroy", (), || {
+        if handle.is_null() || (*handle).is_null() {
+            return;
+        }
+        drop(Box::from_raw(*handle));
+        *handle = ptr::null_mut()
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn cdb_setup() {
-    env_logger::init();
-    eprintln!("setup cdb");
+    catch_unwind_or("cdb_setup", (), || {
+        if let Err(err) = env_logger::try_init() {
+            eprintln!("cdb_setup: logger was already set up: {}", err);
+        }
+        eprintln!("setup cdb");
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn cdb_teardown() {
-    eprintln!("teardown cdb");
+    catch_unwind_or("cdb_teardown", (), || {
+        eprintln!("teardown cdb");
+    })
 }
 
 
@@ -97,17 +249,298 @@ pub extern "C" fn cdb_teardown() {
 mod test {
     use super::*;
     use cdb::backend::Backend;
-    use cdb::cdb_handle;
+    use cdb::{cdb_handle, Compression, Writer, WriterConfig};
+    use std::io::{Cursor, Write};
+    use std::slice;
+    use tempfile::NamedTempFile;
 
     #[test]
     fn cdb_handle_destroy_should_null_out_the_passed_ptr() {
         let be = Backend::noop().unwrap();
 
-        let handle = Box::new(cdb_handle::from(be));
+        let handle = Box::new(cdb_handle::open(be).unwrap());
         let mut p = Box::into_raw(handle) as *mut cdb_handle;
 
         let pp = (&mut p) as *mut *mut cdb_handle;
         unsafe { cdb_handle_destroy(pp) };
         assert!(p.is_null());
     }
+
+    #[test]
+    fn null_pointers_are_errors_not_panics() {
+        let mut kbuf = b"key".to_vec();
+        let k = bstring(&mut kbuf);
+        let mut vbuf = vec![0u8; 4];
+        let mut v = bstring(&mut vbuf);
+
+        let status = unsafe { cdb_get(ptr::null_mut(), &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_ERROR);
+
+        assert!(unsafe { cdb_handle_create(ptr::null()) }.is_null());
+
+        let mut p: *mut cdb_handle = ptr::null_mut();
+        unsafe {
+            cdb_handle_destroy(&mut p);
+            cdb_handle_destroy(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn lookups_on_a_truncated_file_are_errors() {
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::new(&mut data).unwrap();
+            w.put(b"key", b"value").unwrap();
+            w.finish().unwrap();
+        }
+        // cut the file off just past the main table
+        let mut data = data.into_inner();
+        data.truncate(2052);
+
+        let mut handle = Box::into_raw(Box::new(cdb_handle::new(data.into_boxed_slice()).unwrap()));
+
+        let mut kbuf = b"key".to_vec();
+        let k = bstring(&mut kbuf);
+        let mut vbuf = vec![0u8; 10];
+        let mut v = bstring(&mut vbuf);
+
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_ERROR);
+
+        unsafe { cdb_handle_destroy(&mut handle) };
+    }
+
+    #[test]
+    fn cdb_handle_create_verifies_the_checksum() {
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::with_config(&mut data, WriterConfig::default().checksum(true)).unwrap();
+            w.put(b"key", b"value").unwrap();
+            w.finish().unwrap();
+        }
+        let mut data = data.into_inner();
+
+        let create = |data: &[u8], verify_checksum: bool| {
+            let mut ntf = NamedTempFile::new().unwrap();
+            ntf.write_all(data).unwrap();
+
+            let mut pbuf = ntf.path().to_str().unwrap().as_bytes().to_vec();
+            let mut path = bstring(&mut pbuf);
+            let cfg = gen::cdb_handle_create_config {
+                path: &mut path as *mut _ as *mut gen::bstring,
+                load_method: gen::cdb_load_method_CDB_HEAP,
+                verify_checksum,
+            };
+            unsafe { cdb_handle_create(&cfg) }
+        };
+
+        let mut h = create(&data, true);
+        assert!(!h.is_null());
+        unsafe { cdb_handle_destroy(&mut h) };
+
+        data[2048 + 8] ^= 1;
+        assert!(create(&data, true).is_null());
+
+        let mut h = create(&data, false);
+        assert!(!h.is_null());
+        unsafe { cdb_handle_destroy(&mut h) };
+    }
+
+    fn bstring(buf: &mut [u8]) -> bind::bstring {
+        bind::bstring {
+            len: buf.len() as u32,
+            data: buf.as_mut_ptr() as *mut _,
+        }
+    }
+
+    #[test]
+    fn cdb_get_reports_the_size_of_oversized_values() {
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::new(&mut data).unwrap();
+            w.put(b"key", b"a longer value").unwrap();
+            w.finish().unwrap();
+        }
+
+        let handle = Box::into_raw(Box::new(cdb_handle::new(data.into_inner().into_boxed_slice()).unwrap()));
+
+        let mut kbuf = b"key".to_vec();
+        let k = bstring(&mut kbuf);
+
+        let mut vbuf = vec![0u8; 4];
+        let mut v = bstring(&mut vbuf);
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_VALUE_TOO_LARGE);
+        assert_eq!(v.len, 14);
+
+        let mut vbuf = vec![0u8; 14];
+        let mut v = bstring(&mut vbuf);
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_OK);
+        assert_eq!(v.len, 14);
+        assert_eq!(&vbuf[..], b"a longer value");
+
+        let mut kbuf = b"nope".to_vec();
+        let k = bstring(&mut kbuf);
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_NOT_FOUND);
+
+        let mut p = handle;
+        unsafe { cdb_handle_destroy(&mut p) };
+    }
+
+    // the value 'v' points at, which has to still be alive
+    unsafe fn value<'a>(v: &bind::bstring) -> &'a [u8] {
+        slice::from_raw_parts(v.data as *const u8, v.len as usize)
+    }
+
+    #[test]
+    fn cdb_get_ref_points_into_the_handle() {
+        let long = b"a value that compresses ".repeat(10);
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut config = WriterConfig::default();
+            config.compression(Compression::Zstd { level: 0 });
+            let mut w = Writer::with_config(&mut data, &config).unwrap();
+            w.put(b"key", b"short").unwrap();
+            w.put(b"long", &long).unwrap();
+            w.finish().unwrap();
+        }
+
+        let data = data.into_inner();
+        let mut handle = Box::into_raw(Box::new(cdb_handle::new(data.into_boxed_slice()).unwrap()));
+        let backend = unsafe { &cdb_handle::from_raw(handle).backend[..] };
+
+        let mut kbuf = b"key".to_vec();
+        let k = bstring(&mut kbuf);
+        let mut v = bstring(&mut []);
+        let status = unsafe { cdb_get_ref(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_OK);
+        assert_eq!(unsafe { value(&v) }, b"short");
+        let start = v.data as usize - backend.as_ptr() as usize;
+        assert_eq!(&backend[start..(start + 5)], b"short");
+
+        let mut kbuf = b"long".to_vec();
+        let k = bstring(&mut kbuf);
+        let status = unsafe { cdb_get_ref(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_COMPRESSED);
+        assert_eq!(v.len as usize, long.len());
+        assert!(v.data.is_null());
+
+        let mut kbuf = b"nope".to_vec();
+        let k = bstring(&mut kbuf);
+        let status = unsafe { cdb_get_ref(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_NOT_FOUND);
+        assert!(v.data.is_null());
+
+        let status = unsafe { cdb_get_ref(handle, ptr::null(), &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_ERROR);
+
+        unsafe { cdb_handle_destroy(&mut handle) };
+    }
+
+    #[test]
+    fn cdb_get_multi_lends_or_decompresses_values() {
+        let long = b"a value that compresses ".repeat(10);
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut config = WriterConfig::default();
+            config.compression(Compression::Lz4);
+            let mut w = Writer::with_config(&mut data, &config).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            w.put(b"long", &long).unwrap();
+            w.put(b"pink", b"red").unwrap();
+            w.put(b"longer", &b"more than fits ".repeat(20)).unwrap();
+            w.finish().unwrap();
+        }
+        let data = data.into_inner();
+        let mut handle = Box::into_raw(Box::new(cdb_handle::new(data.into_boxed_slice()).unwrap()));
+
+        let mut kbufs: Vec<Vec<u8>> = ["abc", "nope", "longer", "long", "pink"]
+            .iter()
+            .map(|k| k.as_bytes().to_vec())
+            .collect();
+        let keys: Vec<_> = kbufs.iter_mut().map(|k| bstring(k)).collect();
+        let mut vbuf = vec![0u8; 250];
+        let mut buf = bstring(&mut vbuf);
+        let mut values = vec![bstring(&mut []); 5];
+        let mut statuses = vec![gen::cdb_get_status_CDB_GET_ERROR; 5];
+
+        let status = unsafe {
+            cdb_get_multi(
+                handle,
+                keys.as_ptr(),
+                5,
+                &mut buf,
+                values.as_mut_ptr(),
+                statuses.as_mut_ptr(),
+            )
+        };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_OK);
+        assert_eq!(
+            statuses,
+            vec![
+                gen::cdb_get_status_CDB_GET_OK,
+                gen::cdb_get_status_CDB_GET_NOT_FOUND,
+                gen::cdb_get_status_CDB_GET_VALUE_TOO_LARGE,
+                gen::cdb_get_status_CDB_GET_OK,
+                gen::cdb_get_status_CDB_GET_OK,
+            ]
+        );
+        // the short values are lent out of the handle, only the compressed
+        // one that fits is decompressed into the buffer
+        let backend = unsafe { &cdb_handle::from_raw(handle).backend[..] };
+        for &i in &[0, 4] {
+            let start = values[i].data as usize - backend.as_ptr() as usize;
+            assert!(start < backend.len());
+        }
+        assert_eq!(unsafe { (value(&values[0]), value(&values[4])) }, (&b"def"[..], &b"red"[..]));
+        assert_eq!(values[2].len, 300);
+        assert!(values[2].data.is_null());
+        assert_eq!(values[3].data as *mut u8, vbuf.as_mut_ptr());
+        assert_eq!(&vbuf[..long.len()], &long[..]);
+
+        let (v, s) = (values.as_mut_ptr(), statuses.as_mut_ptr());
+        let status = unsafe { cdb_get_multi(handle, ptr::null(), 4, &mut buf, v, s) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_ERROR);
+        let status = unsafe {
+            cdb_get_multi(handle, ptr::null(), 0, &mut buf, ptr::null_mut(), ptr::null_mut())
+        };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_OK);
+
+        unsafe { cdb_handle_destroy(&mut handle) };
+    }
+
+    #[test]
+    fn cdb_get_decompresses_values() {
+        let value = b"a value that compresses ".repeat(10);
+        let mut data = Cursor::new(Vec::new());
+        {
+            let mut config = WriterConfig::default();
+            config.compression(Compression::Zstd { level: 0 });
+            let mut w = Writer::with_config(&mut data, &config).unwrap();
+            w.put(b"key", &value).unwrap();
+            w.finish().unwrap();
+        }
+
+        let data = data.into_inner();
+        let mut handle = Box::into_raw(Box::new(cdb_handle::new(data.into_boxed_slice()).unwrap()));
+
+        let mut kbuf = b"key".to_vec();
+        let k = bstring(&mut kbuf);
+
+        let mut vbuf = vec![0u8; 4];
+        let mut v = bstring(&mut vbuf);
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_VALUE_TOO_LARGE);
+        assert_eq!(v.len as usize, value.len());
+
+        let mut vbuf = vec![0u8; value.len()];
+        let mut v = bstring(&mut vbuf);
+        let status = unsafe { cdb_get(handle, &k, &mut v) };
+        assert_eq!(status, gen::cdb_get_status_CDB_GET_OK);
+        assert_eq!(&vbuf[..], &value[..]);
+
+        unsafe { cdb_handle_destroy(&mut handle) };
+    }
 }
@@ -0,0 +1,169 @@
+//! A Bloom filter over every key in a file, see `WriterConfig::filter`. A
+//! lookup for a key the filter doesn't hold can stop without reading the main
+//! table or a secondary table, which matters when most lookups miss.
+//!
+//! The filter sits between the secondary tables (or the dictionary, if there
+//! is one) and the trailer, which says where:
+//!
+//! ```text
+//! +--------------+---------------------------+
+//! | hashes: u8   | bits: [u8; len - 1]       |
+//! +--------------+---------------------------+
+//! ```
+//!
+//! Bit `n` is bit `n % 8` of byte `n / 8`. A key is in the filter if each of
+//! the `hashes` bits picked for it is set. They're picked by enhanced double
+//! hashing of xxh3 (64 bit, seed 0) of the key: with `h1` and `h2` its low
+//! and high 32 bits, bit `i` is `(h1 + i * h2 + i * i) % m`, where `m` is the
+//! number of bits. The `i * i` keeps small filters, where `h2 % m` is often
+//! 0, from picking the same bit over and over. The key hash the index uses
+//! doesn't come into it, so a djb file's filter is as good as any other's.
+
+use std::f64::consts::LN_2;
+use twox_hash::xxh3;
+
+const MAX_HASHES: u8 = 32;
+
+#[inline]
+fn key_hash(key: &[u8]) -> u64 {
+    xxh3::hash64(key)
+}
+
+// bit 'i' of the ones picked for 'hash' in a filter of 'm' bits
+#[inline]
+fn probe(hash: u64, i: u64, m: u64) -> u64 {
+    let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+    h1.wrapping_add(i.wrapping_mul(h2)).wrapping_add(i * i) % m
+}
+
+/// A filter as it's stored in a file.
+#[derive(Copy, Clone, Debug)]
+pub struct Filter<'a> {
+    hashes: u8,
+    bits: &'a [u8],
+}
+
+impl<'a> Filter<'a> {
+    /// The filter stored in `data`, None if it's too short or picks no bits,
+    /// in which case it can't rule out any key.
+    pub fn new(data: &'a [u8]) -> Option<Filter<'a>> {
+        match data.split_first() {
+            Some((&hashes, bits)) if hashes > 0 && !bits.is_empty() => {
+                Some(Filter { hashes, bits })
+            }
+            _ => None,
+        }
+    }
+
+    /// False if `key` definitely isn't in the file. True if it is, or once in
+    /// a while if it isn't.
+    #[inline]
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let m = self.bits.len() as u64 * 8;
+        let hash = key_hash(key);
+        (0..u64::from(self.hashes)).all(|i| {
+            let bit = probe(hash, i, m);
+            self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// The false positive rate a filter picking this many bits per key is
+    /// sized for.
+    pub fn false_positive_rate(&self) -> f64 {
+        0.5f64.powi(i32::from(self.hashes))
+    }
+}
+
+/// Collects the keys of a file being written, and builds the filter over them
+/// once they're all in.
+pub struct Builder {
+    false_positive_rate: f64,
+    // xxh3 of every key added
+    hashes: Vec<u64>,
+}
+
+impl Builder {
+    pub fn new(false_positive_rate: f64) -> Builder {
+        Builder { false_positive_rate, hashes: Vec::new() }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        self.hashes.push(key_hash(key));
+    }
+
+    /// The filter over every key added, as it's stored in the file. It's
+    /// sized for the number of distinct keys, so keys put more than once
+    /// don't make it any bigger.
+    pub fn build(mut self) -> Vec<u8> {
+        self.hashes.sort_unstable();
+        self.hashes.dedup();
+
+        let n = self.hashes.len().max(1) as f64;
+        let bits = -n * self.false_positive_rate.ln() / (LN_2 * LN_2);
+        let bytes = (bits / 8.0).ceil().max(1.0) as usize;
+        let m = bytes as u64 * 8;
+        let hashes = (m as f64 / n * LN_2).round().max(1.0).min(f64::from(MAX_HASHES)) as u8;
+
+        let mut filter = vec![0u8; 1 + bytes];
+        filter[0] = hashes;
+        for &hash in &self.hashes {
+            for i in 0..u64::from(hashes) {
+                let bit = probe(hash, i, m);
+                filter[1 + (bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(keys: usize, false_positive_rate: f64) -> Vec<u8> {
+        let mut builder = Builder::new(false_positive_rate);
+        for n in 0..keys {
+            builder.add(format!("key{}", n).as_bytes());
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn keys_that_were_added_are_always_found() {
+        let data = build(10_000, 0.01);
+        let filter = Filter::new(&data).unwrap();
+        assert_eq!(filter.hashes, 7);
+        for n in 0..10_000 {
+            assert!(filter.may_contain(format!("key{}", n).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn false_positives_stay_near_the_rate_asked_for() {
+        for &rate in &[0.1, 0.01, 0.001] {
+            let data = build(10_000, rate);
+            let filter = Filter::new(&data).unwrap();
+            let false_positives = (0..100_000)
+                .filter(|n| filter.may_contain(format!("other{}", n).as_bytes()))
+                .count();
+            let measured = false_positives as f64 / 100_000.0;
+            assert!(measured < rate * 1.5, "asked for {}, got {}", rate, measured);
+        }
+    }
+
+    #[test]
+    fn repeated_keys_dont_grow_the_filter() {
+        let mut builder = Builder::new(0.01);
+        for _ in 0..100 {
+            builder.add(b"same");
+        }
+        assert_eq!(builder.build().len(), build(1, 0.01).len());
+    }
+
+    #[test]
+    fn malformed_filters_are_ignored() {
+        assert!(Filter::new(&[]).is_none());
+        assert!(Filter::new(&[3]).is_none());
+        assert!(Filter::new(&[0, 0xff]).is_none());
+    }
+}
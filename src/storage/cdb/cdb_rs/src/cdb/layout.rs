@@ -0,0 +1,158 @@
+//! Records the writer holds back so they can be laid out in a different order
+//! than they were put, see `WriterConfig::layout`.
+//!
+//! Until `finish`, records go to an anonymous spool file (in `TMPDIR`) and the
+//! index points at where they'd have been written. Once every record is in,
+//! they're copied out in the order asked for, and the index is pointed at
+//! where they ended up.
+
+use super::{CDBHash, MainTable, Result};
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use tempfile;
+
+/// The order records are written out in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Layout {
+    /// The order they were put in.
+    Input,
+    /// By the secondary table their key lands in, then by their key's home
+    /// slot in it, so records that share index pages share data pages.
+    Bucket,
+    /// By the hint given to `Writer::put_with_hint`, highest first, so the
+    /// hottest records sit together at the start of the data segment. Records
+    /// with the same hint keep the order they were put in.
+    Hint,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Input
+    }
+}
+
+struct Record {
+    ptr: u64,
+    len: usize,
+    hash: CDBHash,
+    hint: u64,
+}
+
+pub struct Spool {
+    file: BufWriter<File>,
+    // where the records would have started in the file
+    base: u64,
+    len: u64,
+    records: Vec<Record>,
+}
+
+impl Spool {
+    pub fn new(base: u64) -> Result<Spool> {
+        Ok(Spool {
+            file: BufWriter::new(tempfile::tempfile()?),
+            base,
+            len: 0,
+            records: Vec::new(),
+        })
+    }
+
+    /// Where the next record would go in the file, if records weren't being
+    /// held back.
+    pub fn position(&self) -> u64 {
+        self.base + self.len
+    }
+
+    pub fn push(&mut self, record: &[u8], hash: CDBHash, hint: u64) -> Result<()> {
+        self.file.write_all(record)?;
+        self.records.push(Record {
+            ptr: self.position(),
+            len: record.len(),
+            hash,
+            hint,
+        });
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    // the order to write the records out in, as indexes into 'records'.
+    // 'slots' is the size of each secondary table
+    fn order(&self, layout: Layout, main_table: MainTable, slots: &[usize]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.records.len()).collect();
+        let records = &self.records;
+        match layout {
+            Layout::Input => (),
+            Layout::Bucket => order.sort_by_key(|&n| {
+                let hash = records[n].hash;
+                let t = main_table.table(hash);
+                (t, main_table.slot(hash, slots[t]))
+            }),
+            Layout::Hint => order.sort_by_key(|&n| Reverse(records[n].hint)),
+        }
+        order
+    }
+
+    /// Hands the records to `write` in the order `layout` calls for, and
+    /// returns where each of them ended up. `slots` is the size of each of
+    /// the secondary tables of `main_table`.
+    pub fn write_out<W>(
+        self,
+        layout: Layout,
+        main_table: MainTable,
+        slots: &[usize],
+        mut write: W,
+    ) -> Result<Relocation>
+    where
+        W: FnMut(&[u8]) -> Result<()>,
+    {
+        debug_assert_eq!(slots.len(), main_table.buckets());
+
+        let order = self.order(layout, main_table, slots);
+        let Spool { mut file, base, records, .. } = self;
+        file.flush()?;
+        let file = file.get_mut();
+
+        let mut to = vec![0; records.len()];
+        let mut pos = base;
+        let mut buf = Vec::new();
+        for n in order {
+            let rec = &records[n];
+            buf.resize(rec.len, 0);
+            file.seek(SeekFrom::Start(rec.ptr - base))?;
+            file.read_exact(&mut buf)?;
+            write(&buf)?;
+
+            to[n] = pos;
+            pos += rec.len as u64;
+        }
+
+        Ok(Relocation {
+            base,
+            from: records.iter().map(|r| r.ptr).collect(),
+            to,
+        })
+    }
+}
+
+/// Where the records held back in a `Spool` were written out to.
+pub struct Relocation {
+    base: u64,
+    // sorted, since records are spooled in order
+    from: Vec<u64>,
+    to: Vec<u64>,
+}
+
+impl Relocation {
+    /// Where the record the index had at `ptr` ended up. Records that were
+    /// never held back, like the ones already in a file being appended to,
+    /// stay where they are.
+    pub fn ptr(&self, ptr: u64) -> u64 {
+        if ptr < self.base {
+            return ptr;
+        }
+        match self.from.binary_search(&ptr) {
+            Ok(n) => self.to[n],
+            Err(_) => ptr,
+        }
+    }
+}
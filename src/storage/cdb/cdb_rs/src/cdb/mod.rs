@@ -1,35 +1,231 @@
+pub use self::atomic::AtomicWriter;
 pub use self::backend::Backend;
+pub use self::compress::Compression;
 pub use self::errors::CDBError;
+pub use self::layout::Layout;
+pub use self::stats::{BuildStats, SizeStats};
+pub use self::stream::StreamWriter;
+pub use self::writer::{BuildSummary, DuplicateKeys, Writer, WriterConfig};
+use self::compress::{Dictionary, DictionaryRef};
+use self::filter::Filter;
+use self::trailer::Trailer;
 use self::ffi::gen;
-use bytes::{Buf, Bytes, IntoBuf};
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, IntoBuf};
 use cc_binding as bind;
-use ccommon_rs::bstring::BString;
+use ccommon_rs::bstring::BStr;
 use failure;
-use std::cmp;
+use rand;
+use std::borrow::Cow;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::io::{Seek, SeekFrom, Write};
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::path::Path;
 use std::result;
+use twox_hash::xxh3;
 
+pub mod atomic;
 pub mod backend;
+mod compress;
 pub mod errors;
 pub mod ffi;
+mod filter;
 pub mod input;
+mod layout;
+mod spill;
+mod stats;
 pub mod storage;
+pub mod stream;
+pub mod trailer;
+pub mod verify;
+pub mod writer;
 
 pub const STARTING_HASH: u32 = 5381;
-const MAIN_TABLE_SIZE: usize = 256;
 const MAIN_TABLE_SIZE_BYTES: u32 = 2048;
-const END_TABLE_ENTRY_SIZE: u32 = 8;
-const DATA_HEADER_SIZE: u32 = 8;
-const INDEX_ENTRY_SIZE: usize = 8;
+
+// the number of keys Reader::get_many takes a step at a time. enough for their
+// cache misses to overlap, few enough that what's fetched for the first is
+// still in cache by the time the last has been through the step
+const PREFETCH_BATCH: usize = 16;
 
 pub type Result<T> = result::Result<T, failure::Error>;
 
+/// The width of the pointers and lengths stored in a file. Plain djb cdb files
+/// use 32 bits, which caps them at 4 GiB. cdb64 files use 64 bits for every
+/// field and say so in their trailer, which is how readers tell them apart.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PointerWidth {
+    U32,
+    U64,
+}
+
+impl Default for PointerWidth {
+    fn default() -> Self {
+        PointerWidth::U32
+    }
+}
+
+impl PointerWidth {
+    pub fn from_bytes(n: u8) -> Result<PointerWidth> {
+        match n {
+            4 => Ok(PointerWidth::U32),
+            8 => Ok(PointerWidth::U64),
+            _ => Err(CDBError::UnsupportedPointerWidth { width: n }.into()),
+        }
+    }
+
+    /// The size in bytes of a single pointer or length.
+    #[inline]
+    pub fn bytes(self) -> usize {
+        match self {
+            PointerWidth::U32 => 4,
+            PointerWidth::U64 => 8,
+        }
+    }
+
+    /// The largest offset a file of this width can point at.
+    #[inline]
+    pub fn max_offset(self) -> u64 {
+        match self {
+            PointerWidth::U32 => u64::from(u32::max_value()),
+            PointerWidth::U64 => u64::max_value(),
+        }
+    }
+
+    // buckets, index entries and record headers are all a pair of fields
+    #[inline]
+    fn pair_size(self) -> usize {
+        2 * self.bytes()
+    }
+
+    #[inline]
+    fn get<B: Buf>(self, b: &mut B) -> u64 {
+        match self {
+            PointerWidth::U32 => u64::from(b.get_u32_le()),
+            PointerWidth::U64 => b.get_u64_le(),
+        }
+    }
+
+    #[inline]
+    fn put<B: BufMut>(self, b: &mut B, n: u64) {
+        match self {
+            PointerWidth::U32 => b.put_u32_le(n as u32),
+            PointerWidth::U64 => b.put_u64_le(n),
+        }
+    }
+}
+
+/// The size of the main table. djb cdb files always have 256 buckets, files
+/// with more say so in their trailer. In huge files more buckets make for
+/// smaller secondary tables, at the cost of a bigger main table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MainTable {
+    bits: u8,
+}
+
+impl Default for MainTable {
+    fn default() -> Self {
+        MainTable { bits: MainTable::MIN_BITS }
+    }
+}
+
+impl MainTable {
+    pub const MIN_BITS: u8 = 8;
+    pub const MAX_BITS: u8 = 16;
+
+    /// A main table of `2^bits` buckets, from 256 (djb's) to 65536.
+    pub fn with_bits(bits: u8) -> Result<MainTable> {
+        if bits < MainTable::MIN_BITS || bits > MainTable::MAX_BITS {
+            return Err(CDBError::UnsupportedMainTable { bits }.into());
+        }
+        Ok(MainTable { bits })
+    }
+
+    pub fn bits(self) -> u8 {
+        self.bits
+    }
+
+    pub fn buckets(self) -> usize {
+        1 << self.bits
+    }
+
+    /// The size of the table in bytes, in a file of the given width.
+    #[inline]
+    pub fn size(self, width: PointerWidth) -> usize {
+        self.buckets() * width.pair_size()
+    }
+
+    // the bucket 'hash' belongs in
+    #[inline]
+    fn table(self, hash: CDBHash) -> usize {
+        hash.0 as usize & (self.buckets() - 1)
+    }
+
+    // the home slot of 'hash' in a secondary table of 'num_ents' slots. the
+    // low bits of the hash already went into picking the table
+    #[inline]
+    fn slot(self, hash: CDBHash, num_ents: usize) -> usize {
+        (hash.0 as usize >> self.bits) % num_ents
+    }
+}
+
+/// The function keys are hashed with, recorded in the trailer so that readers
+/// use the one the file was written with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HashFn {
+    /// djb's `h * 33 ^ c`, the only one plain djb cdb files can use.
+    Djb,
+    /// xxh3 (64 bit) with the given seed, truncated to 32 bits. Much better
+    /// spread than djb's hash, and with a seed that isn't known in advance
+    /// it's hard to pick keys that all land in the same slot.
+    Xxh3 { seed: u64 },
+}
+
+impl Default for HashFn {
+    fn default() -> Self {
+        HashFn::Djb
+    }
+}
+
+impl HashFn {
+    /// Returns `HashFn::Xxh3` with a randomly chosen seed.
+    pub fn xxh3_random() -> HashFn {
+        HashFn::Xxh3 { seed: rand::random() }
+    }
+
+    pub fn from_id(id: u8, seed: u64) -> Result<HashFn> {
+        match id {
+            0 => Ok(HashFn::Djb),
+            1 => Ok(HashFn::Xxh3 { seed }),
+            _ => Err(CDBError::UnsupportedHashFn { id }.into()),
+        }
+    }
+
+    /// The id the function is recorded under in the trailer.
+    pub fn id(self) -> u8 {
+        match self {
+            HashFn::Djb => 0,
+            HashFn::Xxh3 { .. } => 1,
+        }
+    }
+
+    /// The seed recorded in the trailer, 0 for functions that don't take one.
+    pub fn seed(self) -> u64 {
+        match self {
+            HashFn::Djb => 0,
+            HashFn::Xxh3 { seed } => seed,
+        }
+    }
+
+    #[inline]
+    fn hash(self, key: &[u8]) -> CDBHash {
+        match self {
+            HashFn::Djb => CDBHash::new(key),
+            HashFn::Xxh3 { seed } => CDBHash(xxh3::hash64_with_seed(key, seed) as u32),
+        }
+    }
+}
+
 // idea from https://raw.githubusercontent.com/jothan/cordoba/master/src/lib.rs
 #[derive(Copy, Clone, Eq, PartialEq, Default)]
 struct CDBHash(u32);
@@ -52,16 +248,6 @@ impl CDBHash {
         CDBHash(h)
     }
 
-    #[inline]
-    fn table(self) -> usize {
-        self.0 as usize % MAIN_TABLE_SIZE
-    }
-
-    #[inline]
-    fn slot(self, num_ents: usize) -> usize {
-        (self.0 as usize >> 8) % num_ents
-    }
-
     #[inline]
     fn inner(self) -> u32 {
         self.0
@@ -90,8 +276,8 @@ impl<'a> From<&'a CDBHash> for u32 {
 
 #[derive(Copy, Clone)]
 struct Bucket {
-    ptr: u32,
-    num_ents: u32,
+    ptr: u64,
+    num_ents: u64,
 }
 
 impl fmt::Debug for Bucket {
@@ -106,28 +292,24 @@ impl fmt::Debug for Bucket {
 
 impl Bucket {
     // returns the offset into the db of entry n of this bucket.
-    // panics if n >= num_ents
+    // n must be < num_ents
     #[inline]
-    fn entry_n_pos(self, n: u32) -> IndexEntryPos {
-        assert!(n < self.num_ents);
-        IndexEntryPos(self.ptr + (n * END_TABLE_ENTRY_SIZE))
+    fn entry_n_pos(self, n: u64, width: PointerWidth) -> IndexEntryPos {
+        debug_assert!(n < self.num_ents);
+        // a damaged table can point anywhere, saturate rather than overflow and
+        // let index_entry_at reject the position
+        let off = (n as usize).saturating_mul(width.pair_size());
+        IndexEntryPos((self.ptr as usize).saturating_add(off))
     }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-struct IndexEntryPos(u32);
-
-impl From<IndexEntryPos> for u32 {
-    #[inline]
-    fn from(n: IndexEntryPos) -> Self {
-        n.0
-    }
-}
+struct IndexEntryPos(usize);
 
 impl From<IndexEntryPos> for usize {
     #[inline]
     fn from(n: IndexEntryPos) -> Self {
-        n.0 as usize
+        n.0
     }
 }
 
@@ -147,86 +329,139 @@ pub struct KVRef<'a> {
 struct IndexEntry {
     hash: CDBHash,
     // the hash of the stored key
-    ptr: u32,      // pointer to the absolute position of the data in the db
+    ptr: u64,      // pointer to the absolute position of the data in the db
 }
 
+#[derive(Copy, Clone, Debug)]
 enum LoadMethod {
     HEAP = 1,
     MMAP = 2,
 }
 
 pub struct CDBHandleConfig {
-    bpath: BString,
+    path: String,
     load_method: LoadMethod,
+    verify_checksum: bool,
 }
 
 impl CDBHandleConfig {
-    pub unsafe fn from_raw(ptr: *const gen::cdb_handle_create_config) -> CDBHandleConfig {
-        assert!(!ptr.is_null());
+    /// Copies the settings out of the C side's config. The config, and the
+    /// path it points at, stay owned by the caller.
+    pub unsafe fn from_raw(ptr: *const gen::cdb_handle_create_config) -> Result<CDBHandleConfig> {
+        if ptr.is_null() {
+            return Err(failure::err_msg("cdb_handle_create_config was NULL"));
+        }
 
         let cfg = &*ptr;
-        // this looks nasty, the only reason we have to do this is that rust
-        // sees the type of gen::cdb_handle_create_config.path as a cdb::ffi::gen::bstring
-        // which is the same as cc_binding::bstring, so we just coerce one into the other
-        // to make the compiler happy
-        let bpath = BString::from_raw(&mut *(cfg.path) as *mut _ as *mut bind::bstring);
+        if cfg.path.is_null() {
+            return Err(failure::err_msg("cdb file path was NULL, misconfiguration?"));
+        }
+
+        // gen::bstring and cc_binding::bstring are the same C struct, rust just
+        // sees them as two different types, so we coerce one into the other
+        let path = BStr::from_ptr(cfg.path as *mut bind::bstring).to_utf8_string()?;
+        if path.is_empty() {
+            return Err(failure::err_msg("cdb file path was empty, misconfiguration?"));
+        }
 
         let load_method = match cfg.load_method {
             gen::cdb_load_method_CDB_HEAP => LoadMethod::HEAP,
             gen::cdb_load_method_CDB_MMAP => LoadMethod::MMAP,
-            _ => unreachable!(),
+            n => return Err(failure::err_msg(format!("unknown cdb load method: {}", n))),
         };
 
-        CDBHandleConfig { bpath, load_method }
+        Ok(CDBHandleConfig { path, load_method, verify_checksum: cfg.verify_checksum })
     }
 
-    pub fn path(&self) -> Result<&Path> {
-        ::std::str::from_utf8(&*self.bpath)
-            .map(Path::new)
-            .map_err(|e| e.into())
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
     }
 
     pub fn into_cdb_handle(self) -> Result<cdb_handle> {
-        match self.load_method {
-            LoadMethod::HEAP => self.path()
-                .and_then(backend::Backend::load_path)
-                .map(cdb_handle::from),
-            LoadMethod::MMAP => self.path()
-                .and_then(backend::Backend::mmap_path)
-                .map(cdb_handle::from),
-        }
+        let backend = match (self.load_method, self.verify_checksum) {
+            (LoadMethod::HEAP, false) => Backend::load_path(self.path()),
+            (LoadMethod::HEAP, true) => Backend::load_path_verified(self.path()),
+            (LoadMethod::MMAP, false) => Backend::mmap_path(self.path()),
+            (LoadMethod::MMAP, true) => Backend::mmap_path_verified(self.path()),
+        };
+
+        backend.and_then(cdb_handle::open)
     }
 }
 
 // this struct crosses the FFI boundary (as a pointer), so we give it
 // a snake_case name so it matches the style convention on the C side.
 #[allow(non_camel_case_types)]
-pub struct cdb_handle(Backend);
+pub struct cdb_handle {
+    backend: Backend,
+    trailer: Option<Trailer>,
+    dictionary: Option<Dictionary>,
+}
 
 impl cdb_handle {
     pub unsafe fn from_raw<'a>(ptr: *mut cdb_handle) -> &'a cdb_handle {
         &*(ptr as *mut _)
     }
 
-    pub fn new(b: Box<[u8]>) -> cdb_handle {
-        cdb_handle(Backend::Heap(b))
+    pub fn new(b: Box<[u8]>) -> Result<cdb_handle> {
+        cdb_handle::open(Backend::Heap(Bytes::from(b.into_vec())))
+    }
+
+    /// Wraps `backend`, working out which variant of the format it holds, and
+    /// preparing its dictionary if it has one, up front so lookups don't have
+    /// to.
+    pub fn open(backend: Backend) -> Result<cdb_handle> {
+        let (trailer, dictionary) = {
+            let reader = Reader::new(&backend[..])?;
+            (reader.trailer, reader.dictionary().map(Dictionary::new))
+        };
+        Ok(cdb_handle { backend, trailer, dictionary })
     }
-}
 
-impl From<Backend> for cdb_handle {
-    fn from(be: Backend) -> Self {
-        cdb_handle(be)
+    /// Looks up `key` and returns the value as `Bytes`. With the heap backend the
+    /// result shares the loaded buffer instead of copying the value out of it,
+    /// except for values of 31 bytes or less, which `Bytes` copies as that's
+    /// cheaper than sharing. Values in an mmap'd file are copied, as `Bytes`
+    /// can't refer into a mapping, and compressed values are decompressed into
+    /// a buffer of their own.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let reader = Reader::from(self);
+        let v = match reader.get_ref(key)? {
+            Some(kv) => reader.value(kv.v)?,
+            None => return Ok(None),
+        };
+
+        match (v, &self.backend) {
+            (Cow::Borrowed(v), &Backend::Heap(ref b)) => {
+                let start = v.as_ptr() as usize - b.as_ptr() as usize;
+                Ok(Some(b.slice(start, start + v.len())))
+            }
+            (Cow::Borrowed(v), &Backend::MMap(_)) => Ok(Some(Bytes::from(v))),
+            (Cow::Owned(v), _) => Ok(Some(Bytes::from(v))),
+        }
     }
 }
 
 impl<'a> From<&'a cdb_handle> for Reader<'a> {
     fn from(h: &'a cdb_handle) -> Self {
-        Reader(&h.0[..])
+        let reader = Reader::with_trailer(&h.backend[..], h.trailer);
+        match h.dictionary {
+            Some(ref dictionary) => reader.with_prepared(dictionary),
+            None => reader,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Reader<'a>(&'a [u8]);
+#[derive(Copy, Clone, Debug)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    trailer: Option<Trailer>,
+    width: PointerWidth,
+    hash_fn: HashFn,
+    main_table: MainTable,
+    dictionary: Option<DictionaryRef<'a>>,
+    filter: Option<Filter<'a>>,
+}
 
 pub fn load_bytes_at_path(path: &str) -> Result<Box<[u8]>> {
     let mut f = File::open(path)?;
@@ -236,29 +471,108 @@ pub fn load_bytes_at_path(path: &str) -> Result<Box<[u8]>> {
 }
 
 impl<'a> Reader<'a> {
-    pub fn new<T: AsRef<[u8]>>(r: &'a T) -> Reader<'a> {
-        Reader(r.as_ref())
+    /// Reads the cdb file in `r`. If the file ends in a trailer it's used to
+    /// work out which variant of the format the file is in, otherwise it's read
+    /// as a plain djb cdb file. An error is returned for a trailer that can't be
+    /// parsed or that describes a file this version can't read.
+    pub fn new<T: AsRef<[u8]> + ?Sized>(r: &'a T) -> Result<Reader<'a>> {
+        let data = r.as_ref();
+        let trailer = Trailer::find(data)?.map(|(_, t)| t);
+        Ok(Reader::with_trailer(data, trailer))
+    }
+
+    fn with_trailer(data: &'a [u8], trailer: Option<Trailer>) -> Reader<'a> {
+        let width = trailer.map(|t| t.pointer_width).unwrap_or_default();
+        let hash_fn = trailer.map(|t| t.hash_fn).unwrap_or_default();
+        let main_table = trailer.map(|t| t.main_table).unwrap_or_default();
+        let mut reader = Reader {
+            data,
+            trailer,
+            width,
+            hash_fn,
+            main_table,
+            dictionary: None,
+            filter: None,
+        };
+        reader.dictionary = reader.dictionary().map(DictionaryRef::Stored);
+        reader.filter = reader.filter().and_then(Filter::new);
+        reader
+    }
+
+    // a reader that decompresses values against 'dictionary', which has been
+    // prepared from the file's own
+    fn with_prepared<'b>(self, dictionary: &'b Dictionary) -> Reader<'b>
+        where
+            'a: 'b,
+    {
+        Reader { dictionary: Some(DictionaryRef::Prepared(dictionary)), ..self }
+    }
+
+    /// The trailer the file describes itself with, None for plain djb files.
+    pub fn trailer(&self) -> Option<&Trailer> {
+        self.trailer.as_ref()
+    }
+
+    pub fn pointer_width(&self) -> PointerWidth {
+        self.width
+    }
+
+    pub fn hash_fn(&self) -> HashFn {
+        self.hash_fn
+    }
+
+    /// Whether the file was written with `WriterConfig::compression`, so its
+    /// values are stored with a codec tag, see `Reader::value`.
+    pub fn is_compressed(&self) -> bool {
+        self.trailer.map(|t| t.is_compressed()).unwrap_or(false)
+    }
+
+    /// The zstd dictionary the file's values were compressed against, if it
+    /// has one, see `Compression::ZstdDictionary`.
+    pub fn dictionary(&self) -> Option<&'a [u8]> {
+        let data: &'a [u8] = self.data;
+        self.trailer.and_then(|t| t.dictionary()).and_then(|d| data.get(d))
+    }
+
+    /// The filter over the file's keys, as it's stored, if it has one. See
+    /// `WriterConfig::filter`.
+    pub fn filter(&self) -> Option<&'a [u8]> {
+        let data: &'a [u8] = self.data;
+        self.trailer.and_then(|t| t.filter()).and_then(|f| data.get(f))
+    }
+
+    pub fn main_table(&self) -> MainTable {
+        self.main_table
+    }
+
+    #[inline]
+    fn main_table_size(&self) -> usize {
+        self.main_table.size(self.width)
     }
 
-    // TODO: perform basic sanity checks of data (gee i wish there was a checksum
-    // somwhere in this data format HINT HINT)
-    //
-    // * does the secondary index fall safely in the correct index range?
-    // * do data pointers point at the data segment?
+    // whole-file checksums live in the optional trailer (see trailer.rs) and
+    // are checked on load by Backend::*_verified. structural sanity checks of
+    // the tables and data pointers are done by verify::verify
 
     #[inline]
     fn bucket_at(&self, idx: usize) -> Result<Bucket> {
-        assert!(idx < MAIN_TABLE_SIZE);
+        debug_assert!(idx < self.main_table.buckets());
+
+        let main_table = self.main_table_size();
+        if self.len() < main_table {
+            return Err(CDBError::MainTableTruncated {
+                len: self.len(),
+                min_len: main_table,
+            }.into());
+        }
 
-        let off = 8 * idx;
+        let off = self.width.pair_size() * idx;
 
-        let slice = self[off..(off + 8)].as_ref();
-        let mut b = slice.into_buf();
-        assert_eq!(slice.len(), 8);
+        let mut b = self[off..(off + self.width.pair_size())].into_buf();
         trace!("bucket_at idx: {}, got buf: {:?}", idx, b);
 
-        let ptr = b.get_u32_le();
-        let num_ents = b.get_u32_le();
+        let ptr = self.width.get(&mut b);
+        let num_ents = self.width.get(&mut b);
 
         Ok(Bucket { ptr, num_ents })
     }
@@ -267,64 +581,385 @@ impl<'a> Reader<'a> {
     #[inline]
     fn index_entry_at(&self, pos: IndexEntryPos) -> Result<IndexEntry> {
         let pos: usize = pos.into();
-
-        if (pos as u32) < MAIN_TABLE_SIZE_BYTES {
-            panic!("position {:?} was in the main table!", pos)
+        let end = pos.saturating_add(self.width.pair_size());
+        let main_table = self.main_table_size();
+
+        if pos < main_table || end > self.len() {
+            return Err(CDBError::TableOutOfIndex {
+                valid_range: main_table..self.len(),
+                table: pos..end,
+            }.into());
         }
 
-        let mut b = self[pos..(pos + 8)].into_buf();
-        let hash = CDBHash(b.get_u32_le());
-        let ptr = b.get_u32_le();
+        let mut b = self[pos..end].into_buf();
+        let hash = CDBHash(self.width.get(&mut b) as u32);
+        let ptr = self.width.get(&mut b);
 
         Ok(IndexEntry { hash, ptr })
     }
 
     #[inline]
-    fn get_kv_ref(&'a self, ie: IndexEntry) -> Result<KVRef<'a>> {
-        let p = ie.ptr as usize;
-        let b = self[p..(p + DATA_HEADER_SIZE as usize)].as_ref();
+    fn get_kv_ref(&self, ie: IndexEntry) -> Result<KVRef<'a>> {
+        self.kv_ref_at(ie.ptr as usize)
+    }
+
+    // returns the record whose header starts at absolute position 'p' in the db
+    #[inline]
+    fn kv_ref_at(&self, p: usize) -> Result<KVRef<'a>> {
+        let data: &'a [u8] = self.data;
+        let valid_range = self.main_table_size()..data.len();
+        let kstart = p.saturating_add(self.width.pair_size());
+
+        if p < valid_range.start || p >= valid_range.end {
+            return Err(CDBError::IndexOutOfDataSegment { valid_range, ptr_val: p }.into());
+        } else if kstart > valid_range.end {
+            return Err(CDBError::RecordOverrun { valid_range, record: p..kstart }.into());
+        }
 
-        let ksize = b[..4].into_buf().get_u32_le() as usize;
-        let vsize = b[4..].into_buf().get_u32_le() as usize;
+        let mut b = data[p..kstart].into_buf();
+        let ksize = self.width.get(&mut b) as usize;
+        let vsize = self.width.get(&mut b) as usize;
 
-        let kstart = p + DATA_HEADER_SIZE as usize;
-        let vstart = (kstart + ksize) as usize;
+        // the lengths come straight from the file, so saturate rather than
+        // overflow and let the bounds check reject them
+        let vstart = kstart.saturating_add(ksize);
+        let vend = vstart.saturating_add(vsize);
+        if vend > valid_range.end {
+            return Err(CDBError::RecordOverrun { valid_range, record: p..vend }.into());
+        }
 
-        let k = &self[kstart..(kstart + ksize)];
-        let v = &self[vstart..(vstart + vsize)];
+        let k = &data[kstart..vstart];
+        let v = &data[vstart..vend];
 
         Ok(KVRef { k, v })
     }
 
+    /// Returns an iterator over every record in the data segment, in the order
+    /// they were written. The data segment runs from the end of the main table
+    /// up to the first secondary table, which is where bucket 0 points. As with
+    /// `get_ref`, values are as they're stored.
+    pub fn iter(&self) -> Result<Iter<'a>> {
+        let end = self.bucket_at(0)?.ptr as usize;
+        let main_table = self.main_table_size();
+
+        if end < main_table || end > self.len() {
+            return Err(CDBError::IndexOutOfDataSegment {
+                valid_range: main_table..self.len(),
+                ptr_val: end,
+            }.into());
+        }
+
+        Ok(Iter {
+            reader: *self,
+            pos: main_table,
+            end,
+        })
+    }
+
+    /// Looks up `key` and copies its value into `buf`, decompressing it if it
+    /// was stored compressed, and returns the length of the value. If the value
+    /// doesn't fit, `CDBError::ValueTooLarge` is returned with the real size of
+    /// the value and `buf` is left untouched.
     pub fn get(&self, key: &[u8], buf: &mut [u8]) -> Result<Option<usize>> {
-        let hash = CDBHash::new(key);
-        let bucket = self.bucket_at(hash.table())?;
+        match self.get_ref(key)? {
+            Some(kv) => self.copy_value(kv.v, buf).map(Some),
+            None => Ok(None),
+        }
+    }
 
-        if bucket.num_ents == 0 {
-            trace!("bucket empty, returning none");
+    // copies the value held by 'stored' into 'buf' the way 'get' does
+    fn copy_value(&self, stored: &[u8], buf: &mut [u8]) -> Result<usize> {
+        if self.is_compressed() {
+            compress::decode_into(stored, buf, self.dictionary).map_err(|e| e.into())
+        } else {
+            copy_slice(buf, stored)
+        }
+    }
+
+    /// The value held by `stored`, a value as it's stored in the file. That's
+    /// `stored` itself unless the file is compressed, where it's decompressed
+    /// into a new buffer if it had been compressed. Readers made from a
+    /// `cdb_handle` decompress against the dictionary it prepared when it was
+    /// opened, other readers prepare it again for every value.
+    pub fn value(&self, stored: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if self.is_compressed() {
+            compress::decode(stored, self.dictionary).map_err(|e| e.into())
+        } else {
+            Ok(Cow::Borrowed(stored))
+        }
+    }
+
+    /// The value held by `stored` as a slice of the file, without copying it.
+    /// None if it was stored compressed, in which case it has to be
+    /// decompressed with `Reader::value`.
+    pub fn value_ref(&self, stored: &'a [u8]) -> Result<Option<&'a [u8]>> {
+        if self.is_compressed() && compress::is_compressed(stored) {
             return Ok(None);
         }
+        match self.value(stored)? {
+            Cow::Borrowed(v) => Ok(Some(v)),
+            Cow::Owned(_) => Ok(None),
+        }
+    }
+
+    /// Looks up `key` and returns the matching record as slices into the
+    /// underlying data, without copying the value out. The value is as it's
+    /// stored, pass it to `Reader::value` if the file may be compressed.
+    pub fn get_ref(&self, key: &[u8]) -> Result<Option<KVRef<'a>>> {
+        match self.get_all(key)?.next() {
+            Some(Ok(kv)) => Ok(Some(kv)),
+            Some(Err(err)) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over every record stored under `key`, in the order
+    /// they were written. The format allows a key to be stored any number of
+    /// times; `get` and `get_ref` only ever see the first of them. As with
+    /// `get_ref`, values are as they're stored.
+    ///
+    /// In a file with a filter, keys it rules out aren't looked for in the
+    /// index at all, so this and every other lookup comes back empty without
+    /// reading the main table.
+    pub fn get_all<'k>(&self, key: &'k [u8]) -> Result<GetAll<'a, 'k>> {
+        let hash = self.lookup_hash(key);
+        self.lookup(key, hash)
+    }
 
-        let slot = hash.slot(bucket.num_ents as usize);
+    /// Looks up each of `keys`, returning what `get_ref` would for each of
+    /// them, in the same order.
+    ///
+    /// Rather than follow each key from its bucket to its index entry to its
+    /// record before starting on the next, with a cache miss at every step,
+    /// it takes the keys a batch at a time through each step, asking the CPU
+    /// to fetch what the next step reads as it goes. The misses of a batch
+    /// then overlap instead of being waited on one after another, which pays
+    /// off from a handful of keys up. The hints are only given on x86_64,
+    /// elsewhere this is the same as calling `get_ref` for each key.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Result<Option<KVRef<'a>>>> {
+        let width = self.width;
+        let mut found = Vec::with_capacity(keys.len());
+
+        for batch in keys.chunks(PREFETCH_BATCH) {
+            // hash the keys, and fetch their buckets
+            let hashes: Vec<Option<CDBHash>> = batch
+                .iter()
+                .map(|key| {
+                    let hash = self.lookup_hash(key.as_ref());
+                    if let Some(hash) = hash {
+                        prefetch(self.data, width.pair_size() * self.main_table.table(hash));
+                    }
+                    hash
+                })
+                .collect();
+
+            // read the buckets, and fetch the entry at each key's home slot
+            let lookups: Vec<Result<GetAll>> = batch
+                .iter()
+                .zip(hashes)
+                .map(|(key, hash)| {
+                    let lookup = self.lookup(key.as_ref(), hash)?;
+                    if let Some(pos) = lookup.home() {
+                        prefetch(self.data, pos.into());
+                    }
+                    Ok(lookup)
+                })
+                .collect();
+
+            // fetch the records the home entries point at, where they're for
+            // the key's hash
+            for lookup in &lookups {
+                let pos = match *lookup {
+                    Ok(ref lookup) => lookup.home().map(|pos| (lookup.hash, pos)),
+                    Err(_) => None,
+                };
+                if let Some((hash, pos)) = pos {
+                    match self.index_entry_at(pos) {
+                        Ok(ie) if ie.ptr != 0 && ie.hash == hash => {
+                            prefetch(self.data, ie.ptr as usize)
+                        }
+                        _ => (),
+                    }
+                }
+            }
 
-        for x in 0..bucket.num_ents {
-            let index_entry_pos = bucket.entry_n_pos((x + slot as u32) % bucket.num_ents);
+            for lookup in lookups {
+                found.push(lookup.and_then(|mut lookup| match lookup.next() {
+                    Some(kv) => kv.map(Some),
+                    None => Ok(None),
+                }));
+            }
+        }
 
-            let idx_ent = self.index_entry_at(index_entry_pos)?;
+        found
+    }
+
+    // the hash to look 'key' up by, or None if the file's filter rules it out
+    #[inline]
+    fn lookup_hash(&self, key: &[u8]) -> Option<CDBHash> {
+        match self.filter {
+            Some(ref filter) if !filter.may_contain(key) => None,
+            _ => Some(self.hash_fn.hash(key)),
+        }
+    }
+
+    // starts the lookup of 'key' by the hash from 'lookup_hash'
+    fn lookup<'k>(&self, key: &'k [u8], hash: Option<CDBHash>) -> Result<GetAll<'a, 'k>> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => {
+                return Ok(GetAll {
+                    reader: *self,
+                    key,
+                    hash: CDBHash::default(),
+                    bucket: Bucket { ptr: 0, num_ents: 0 },
+                    slot: 0,
+                    probe: 0,
+                })
+            }
+        };
+
+        let bucket = self.bucket_at(self.main_table.table(hash))?;
+
+        let slot = if bucket.num_ents == 0 {
+            trace!("bucket empty, returning none");
+            0
+        } else {
+            self.main_table.slot(hash, bucket.num_ents as usize) as u64
+        };
+
+        Ok(GetAll {
+            reader: *self,
+            key,
+            hash,
+            bucket,
+            slot,
+            probe: 0,
+        })
+    }
+
+    /// Returns the number of records stored under `key`.
+    pub fn count(&self, key: &[u8]) -> Result<usize> {
+        let mut n = 0;
+        for kv in self.get_all(key)? {
+            kv?;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// Iterator over the records stored under a single key, see `Reader::get_all`.
+pub struct GetAll<'a, 'k> {
+    reader: Reader<'a>,
+    key: &'k [u8],
+    hash: CDBHash,
+    bucket: Bucket,
+    slot: u64,
+    probe: u64,
+}
+
+impl<'a, 'k> GetAll<'a, 'k> {
+    fn stop(&mut self) {
+        self.probe = self.bucket.num_ents;
+    }
+
+    // where the entry the lookup starts at is, if there's one to look at
+    #[inline]
+    fn home(&self) -> Option<IndexEntryPos> {
+        if self.probe < self.bucket.num_ents {
+            Some(self.bucket.entry_n_pos(self.slot, self.reader.width))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, 'k> Iterator for GetAll<'a, 'k> {
+    type Item = Result<KVRef<'a>>;
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        // records with the same key are placed along the probe sequence in the
+        // order they were written, so walking it from the key's slot until we
+        // hit an empty entry yields them in insertion order.
+        while self.probe < self.bucket.num_ents {
+            let n = (self.probe + self.slot) % self.bucket.num_ents;
+            self.probe += 1;
+
+            let pos = self.bucket.entry_n_pos(n, self.reader.width);
+            let idx_ent = match self.reader.index_entry_at(pos) {
+                Ok(ie) => ie,
+                Err(err) => {
+                    self.stop();
+                    return Some(Err(err));
+                }
+            };
 
             if idx_ent.ptr == 0 {
-                return Ok(None);
-            } else if idx_ent.hash == hash {
-                let kv = self.get_kv_ref(idx_ent)?;
-                if &kv.k[..] == key {
-                    return Ok(Some(copy_slice(buf, kv.v)));
-                } else {
-                    continue;
+                self.stop();
+                return None;
+            } else if idx_ent.hash == self.hash {
+                match self.reader.get_kv_ref(idx_ent) {
+                    Ok(kv) => {
+                        if kv.k == self.key {
+                            return Some(Ok(kv));
+                        }
+                    }
+                    Err(err) => {
+                        self.stop();
+                        return Some(Err(err));
+                    }
                 }
             }
         }
 
-        Ok(None)
+        None
+    }
+}
+
+/// Iterator over the records of a cdb file, see `Reader::iter`.
+pub struct Iter<'a> {
+    reader: Reader<'a>,
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Iter<'a> {
+    // stops the iteration, returning an error for the record 'record' that
+    // would have run past the end of the data segment
+    fn overrun(&mut self, record: Range<usize>) -> failure::Error {
+        let valid_range = self.reader.main_table_size()..self.end;
+        self.pos = self.end;
+        CDBError::RecordOverrun { valid_range, record }.into()
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<KVRef<'a>>;
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+
+        let pos = self.pos;
+        let width = self.reader.width;
+        let header_end = pos + width.pair_size();
+        if header_end > self.end {
+            return Some(Err(self.overrun(pos..header_end)));
+        }
+
+        let mut b = self.reader[pos..header_end].into_buf();
+        let ksize = width.get(&mut b) as usize;
+        let vsize = width.get(&mut b) as usize;
+
+        let next = header_end.saturating_add(ksize).saturating_add(vsize);
+        if next > self.end {
+            return Some(Err(self.overrun(pos..next)));
+        }
+
+        self.pos = next;
+        Some(self.reader.kv_ref_at(pos))
     }
 }
 
@@ -332,20 +967,39 @@ impl<'a> Deref for Reader<'a> {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
-        self.0
+        self.data
+    }
+}
+
+// hints to the CPU that 'data[pos]' is about to be read. it's only a hint, so
+// positions past the end of 'data' are ignored rather than an error
+#[inline]
+fn prefetch(data: &[u8], pos: usize) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        if pos < data.len() {
+            _mm_prefetch(data.as_ptr().add(pos) as *const i8, _MM_HINT_T0);
+        }
     }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = (data, pos);
 }
 
 #[inline]
-fn copy_slice(dst: &mut [u8], src: &[u8]) -> usize {
-    let n = cmp::min(dst.len(), src.len());
-    dst[0..n].copy_from_slice(&src[0..n]);
-    n
+fn copy_slice(dst: &mut [u8], src: &[u8]) -> Result<usize> {
+    if src.len() > dst.len() {
+        return Err(CDBError::value_too_large(dst.len(), src.len()).into());
+    }
+
+    dst[..src.len()].copy_from_slice(src);
+    Ok(src.len())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
     use tempfile::NamedTempFile;
 
 
@@ -391,155 +1045,536 @@ mod tests {
                 let (k, v) = kv.clone();
                 w.put(&k.into_bytes(), &v.into_bytes())?;
             }
+            w.finish()?;
         }
 
         let data = f(&mut ntf)?;
-        let cdb = Reader::new(&data);
+        let cdb = Reader::new(&data).unwrap();
         validate(&kvs, &cdb)
     }
 
+    fn write_cdb(kvs: &Vec<(String, String)>) -> Result<Vec<u8>> {
+        write_cdb_with(kvs, &WriterConfig::default())
+    }
+
+    fn write_cdb_with(kvs: &Vec<(String, String)>, config: &WriterConfig) -> Result<Vec<u8>> {
+        let mut ntf = NamedTempFile::new()?;
+
+        {
+            let mut w = Writer::with_config(ntf.as_file_mut(), config)?;
+            for (k, v) in kvs {
+                w.put(k.as_bytes(), v.as_bytes())?;
+            }
+            w.finish()?;
+        }
+
+        let mut buf = Vec::new();
+        ntf.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
     #[test]
-    fn round_trip_boxed_slice_test() {
+    fn checksummed_files_load_verified() {
         let pairs = kvs();
+        let mut ntf = NamedTempFile::new().unwrap();
 
-        load_and_validate_cdb(&pairs, |ntf| {
-            let mut buf = Vec::new();
-            ntf.read_to_end(&mut buf)?;
-            Ok(buf.into_boxed_slice())
-        }).unwrap()
+        {
+            let mut w = Writer::with_config(ntf.as_file_mut(), WriterConfig::default().checksum(true))
+                .unwrap();
+            for (k, v) in &pairs {
+                w.put(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            w.finish().unwrap();
+        }
+
+        // the trailer doesn't get in the way of lookups
+        for be in &[
+            Backend::load_path_verified(ntf.path()).unwrap(),
+            Backend::mmap_path_verified(ntf.path()).unwrap(),
+        ] {
+            validate(&pairs, &Reader::new(be).unwrap()).unwrap();
+        }
+
+        // flip a bit in the first value
+        let mut data = Vec::new();
+        ntf.read_to_end(&mut data).unwrap();
+        data[MAIN_TABLE_SIZE_BYTES as usize + 8 + 3] ^= 1;
+        ntf.as_file_mut().seek(SeekFrom::Start(0)).unwrap();
+        ntf.as_file_mut().write_all(&data).unwrap();
+
+        for res in vec![
+            Backend::load_path_verified(ntf.path()),
+            Backend::mmap_path_verified(ntf.path()),
+        ] {
+            let err = res.err().unwrap();
+            match err.downcast_ref::<CDBError>() {
+                Some(CDBError::ChecksumMismatch { .. }) => (),
+                _ => panic!("expected ChecksumMismatch, got: {:?}", err),
+            }
+        }
     }
 
     #[test]
-    fn round_trip_heap_backend() {
+    fn verified_loading_requires_a_checksum() {
+        let data = write_cdb(&kvs()).unwrap();
+        let mut ntf = NamedTempFile::new().unwrap();
+        ntf.write_all(&data).unwrap();
+
+        assert!(Backend::load_path(ntf.path()).is_ok());
+
+        let err = Backend::load_path_verified(ntf.path()).err().unwrap();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::MissingChecksum) => (),
+            _ => panic!("expected MissingChecksum, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn files_describe_themselves_in_the_trailer() {
         let pairs = kvs();
 
-        load_and_validate_cdb(&pairs, |ntf| {
-            Backend::load(ntf.as_file_mut())
-        }).unwrap()
+        let data = write_cdb(&pairs).unwrap();
+        assert!(Reader::new(&data).unwrap().trailer().is_none());
+
+        let data = write_cdb_with(&pairs, WriterConfig::default().trailer(true)).unwrap();
+        {
+            let cdb = Reader::new(&data).unwrap();
+            assert_eq!(cdb.trailer(), Some(&Trailer::default()));
+            validate(&pairs, &cdb).unwrap();
+        }
+
+        let data = write_cdb_with(&pairs, WriterConfig::default().pointer_width(PointerWidth::U64))
+            .unwrap();
+        let handle = cdb_handle::new(data.into_boxed_slice()).unwrap();
+        let cdb = Reader::from(&handle);
+        assert_eq!(cdb.trailer().map(|t| t.pointer_width), Some(PointerWidth::U64));
+        assert_eq!(cdb.trailer().map(|t| t.hash_fn), Some(HashFn::Djb));
+        validate(&pairs, &cdb).unwrap();
     }
 
     #[test]
-    fn round_trip_mmap_backend() {
+    fn round_trip_xxh3() {
         let pairs = kvs();
+        let hash_fn = HashFn::xxh3_random();
+
+        for &width in &[PointerWidth::U32, PointerWidth::U64] {
+            let data = write_cdb_with(
+                &pairs,
+                WriterConfig::default().hash_fn(hash_fn).pointer_width(width),
+            ).unwrap();
+
+            let cdb = Reader::new(&data).unwrap();
+            assert_eq!(cdb.hash_fn(), hash_fn);
+            validate(&pairs, &cdb).unwrap();
+
+            let report = verify::verify(&cdb);
+            assert!(report.is_ok(), "{:?}", report);
+
+            // without the trailer the file reads as a djb one, and the keys
+            // can't be found where djb's hash says they are
+            let djb = Reader::with_trailer(&data, None);
+            let misses = pairs.iter()
+                .filter(|(k, _)| djb.get_ref(k.as_bytes()).map(|kv| kv.is_none()).unwrap_or(true))
+                .count();
+            assert!(misses > 0);
+        }
+    }
 
-        load_and_validate_cdb(&pairs, |ntf| {
-            Backend::mmap(ntf.as_file_mut())
-        }).unwrap()
+    #[test]
+    fn round_trip_load_factors_and_main_tables() {
+        let pairs = kvs();
+
+        for &bits in &[8, 12] {
+            for &load_factor in &[0.25, 0.5, 0.9, 1.0] {
+                for &width in &[PointerWidth::U32, PointerWidth::U64] {
+                    let main_table = MainTable::with_bits(bits).unwrap();
+                    let mut config = WriterConfig::default();
+                    config
+                        .main_table(main_table)
+                        .load_factor(load_factor)
+                        .pointer_width(width)
+                        .checksum(true);
+                    let data = write_cdb_with(&pairs, &config).unwrap();
+
+                    let cdb = Reader::new(&data).unwrap();
+                    assert_eq!(cdb.main_table().buckets(), 1 << bits);
+                    validate(&pairs, &cdb).unwrap();
+                    assert!(cdb.get_ref(b"not there").unwrap().is_none());
+
+                    let report = verify::verify(&cdb);
+                    assert!(report.is_ok(), "{:?}", report);
+                    assert_eq!(report.data_segment.start, main_table.size(width));
+
+                    let flags = cdb.trailer().unwrap().flags;
+                    assert_eq!(flags & trailer::FLAG_MAIN_TABLE != 0, bits != 8);
+                }
+            }
+        }
     }
-}
 
-fn ready_buf(size: usize) -> BytesMut {
-    let mut b = BytesMut::with_capacity(size);
-    b.reserve(size);
-    b
-}
+    #[test]
+    fn load_factor_sizes_the_secondary_tables() {
+        let pairs = kvs();
+        let index_len = |load_factor: f64| {
+            let mut config = WriterConfig::default();
+            config.load_factor(load_factor);
+            let data = write_cdb_with(&pairs, &config).unwrap();
+            let report = verify::verify(&Reader::new(&data).unwrap());
+            report.index.len()
+        };
 
-pub struct Writer<'a, F>
-    where
-        F: Write + Seek + 'a,
-{
-    file: &'a mut F,
-    index: Vec<Vec<IndexEntry>>,
-}
+        // every table has an extra, empty entry
+        let entries = pairs.len() + 256;
+        assert_eq!(index_len(0.5), entries * 2 * 8);
+        assert_eq!(index_len(1.0), entries * 8);
+        assert!(index_len(0.8) < index_len(0.5));
+
+        for &load_factor in &[0.0, -1.0, 1.5] {
+            let mut cur = Cursor::new(Vec::new());
+            let config = WriterConfig::default().load_factor(load_factor).clone();
+            let err = Writer::with_config(&mut cur, &config).err().unwrap();
+            match err.downcast_ref::<CDBError>() {
+                Some(CDBError::InvalidLoadFactor { .. }) => (),
+                _ => panic!("expected InvalidLoadFactor, got: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_cdb64() {
+        let pairs = kvs();
 
-impl<'a, F> Writer<'a, F>
-    where
-        F: Write + Seek + 'a,
-{
-    pub fn new(file: &'a mut F) -> Result<Writer<'a, F>> {
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(&[0u8; MAIN_TABLE_SIZE_BYTES as usize])?;
+        for &checksum in &[false, true] {
+            let data = write_cdb_with(
+                &pairs,
+                WriterConfig::default().pointer_width(PointerWidth::U64).checksum(checksum),
+            ).unwrap();
 
-        Ok(Writer {
-            file,
-            index: vec![vec![IndexEntry::default()]; 256],
-        })
+            let cdb = Reader::new(&data).unwrap();
+            assert_eq!(cdb.pointer_width(), PointerWidth::U64);
+            assert_eq!(cdb.bucket_at(0).unwrap().ptr as usize, 4096 + 4 * 16 + 28);
+
+            validate(&pairs, &cdb).unwrap();
+            assert_eq!(cdb.iter().unwrap().count(), pairs.len());
+
+            let report = verify::verify(&cdb);
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.entries, pairs.len());
+
+            assert_eq!(trailer::verify_checksum(&data).is_ok(), checksum);
+
+            let handle = cdb_handle::new(data.into_boxed_slice()).unwrap();
+            assert_eq!(&handle.get_bytes(b"apple").unwrap().unwrap()[..], b"grape");
+        }
     }
 
-    fn seek(&mut self, sf: SeekFrom) -> Result<u32> {
-        self.file.seek(sf).map(|n| n as u32).map_err(|e| e.into())
+    #[test]
+    fn iter_yields_records_in_file_order() {
+        let pairs = kvs();
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data).unwrap();
+
+        let recs: Vec<(String, String)> = cdb.iter()
+            .unwrap()
+            .map(|kv| {
+                let kv = kv.unwrap();
+                (String::from_utf8(kv.k.to_vec()).unwrap(), String::from_utf8(kv.v.to_vec()).unwrap())
+            })
+            .collect();
+
+        assert_eq!(recs, pairs);
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        let ptr = self.seek(SeekFrom::Current(0))?;
-        let mut buf = ready_buf(INDEX_ENTRY_SIZE + key.len() + value.len());
+    #[test]
+    fn iter_reports_truncated_record() {
+        let pairs = kvs();
+        let mut data = write_cdb(&pairs).unwrap();
 
-        buf.put_u32_le(key.len() as u32);
-        buf.put_u32_le(value.len() as u32);
-        buf.extend_from_slice(key);
-        buf.extend_from_slice(value);
+        // make the last record's value length run past the data segment
+        let last = MAIN_TABLE_SIZE_BYTES as usize
+            + pairs[..3].iter().map(|(k, v)| 8 + k.len() + v.len()).sum::<usize>();
+        data[last + 4] = 0xff;
 
-        self.file.write_all(&buf[..])?;
+        let cdb = Reader::new(&data).unwrap();
+        let recs: Vec<Result<KVRef>> = cdb.iter().unwrap().collect();
 
-        let hash = CDBHash::new(key);
-        self.index[hash.table() as usize].push(IndexEntry { hash, ptr });
-        Ok(())
+        assert_eq!(recs.len(), 4);
+        assert!(recs[..3].iter().all(|r| r.is_ok()));
+        assert!(recs[3].is_err());
     }
 
-    fn finalize(&mut self) -> Result<()> {
-        let mut buckets: Vec<Bucket> = Vec::with_capacity(256);
-        self.file.seek(SeekFrom::End(0))?;
+    #[test]
+    fn get_ref_borrows_from_the_backing_data() {
+        let pairs = kvs();
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data).unwrap();
 
-        let idx = self.index.clone();
+        let range = data.as_ptr() as usize..(data.as_ptr() as usize + data.len());
 
-        for tbl in idx {
-            let length = (tbl.len() << 1) as u32;
-            let mut ordered: Vec<IndexEntry> = vec![IndexEntry::default(); length as usize];
-            for idx_ent in tbl {
-                let slot = idx_ent.hash.slot(length as usize);
-                for i in 0..length {
-                    let j = (i + slot as u32) % length;
-                    if ordered[j as usize].ptr == 0 {
-                        ordered[j as usize] = idx_ent;
-                        break;
-                    }
-                }
-            }
+        for (k, v) in &pairs {
+            let kv = cdb.get_ref(k.as_bytes()).unwrap().unwrap();
+            assert_eq!(kv.k, k.as_bytes());
+            assert_eq!(kv.v, v.as_bytes());
+            assert!(range.contains(&(kv.v.as_ptr() as usize)));
+        }
 
-            // move to EOF and write out the secondary index entries, constructing the
-            // primary table as we go ('buckets')
-            //
-            buckets.push(Bucket {
-                ptr: self.seek(SeekFrom::End(0))?,
-                num_ents: length,
-            });
+        assert!(cdb.get_ref(b"1233").unwrap().is_none());
+    }
+
+    #[test]
+    fn get_bytes_shares_the_heap_buffer() {
+        // shorter values are copied inline by Bytes
+        let pairs: Vec<(String, String)> = kvs()
+            .into_iter()
+            .map(|(k, v)| (k, v.repeat(32)))
+            .collect();
+        let data = write_cdb(&pairs).unwrap();
+        let handle = cdb_handle::new(data.into_boxed_slice()).unwrap();
+
+        let buffer = match handle.backend {
+            Backend::Heap(ref b) => b.as_ptr() as usize..b.as_ptr() as usize + b.len(),
+            _ => unreachable!(),
+        };
 
-            let mut buf = ready_buf((ordered.len() * 8) as usize);
+        for (k, v) in &pairs {
+            let b = handle.get_bytes(k.as_bytes()).unwrap().unwrap();
+            assert_eq!(&b[..], v.as_bytes());
+            let value = b.as_ptr() as usize;
+            assert!(buffer.start <= value && value + b.len() <= buffer.end);
+        }
+
+        assert!(handle.get_bytes(b"1233").unwrap().is_none());
+    }
+
+    #[test]
+    fn compressed_values_are_decompressed_on_lookup() {
+        let mut pairs = kvs();
+        pairs.push(("json".to_string(), "{\"pink\": \"red\"}, ".repeat(50)));
+        let plain = write_cdb(&pairs).unwrap();
+
+        for &compression in &[Compression::Zstd { level: 3 }, Compression::Lz4] {
+            let mut config = WriterConfig::default();
+            config.compression(compression).min_compressed_size(16);
+            let data = write_cdb_with(&pairs, &config).unwrap();
+            let cdb = Reader::new(&data).unwrap();
+
+            assert!(cdb.is_compressed());
+            assert!(verify::verify(&cdb).is_ok());
+            // the long value is stored compressed, the rest as they are
+            assert!(data.len() + 600 < plain.len());
+
+            validate(&pairs[..4].to_vec(), &cdb).unwrap();
+            let json = &pairs[4].1;
+            let mut buf = vec![0u8; json.len()];
+            assert_eq!(cdb.get(b"json", &mut buf).unwrap(), Some(json.len()));
+            assert_eq!(&buf[..], json.as_bytes());
+
+            let kv = cdb.get_ref(b"json").unwrap().unwrap();
+            assert!(kv.v.len() < json.len());
+            assert_eq!(&cdb.value(kv.v).unwrap()[..], json.as_bytes());
+            assert_eq!(cdb.value_ref(kv.v).unwrap(), None);
+            let kv = cdb.get_ref(b"pink").unwrap().unwrap();
+            assert_eq!(cdb.value_ref(kv.v).unwrap(), Some(&b"red"[..]));
+
+            let handle = cdb_handle::new(data.clone().into_boxed_slice()).unwrap();
+            assert_eq!(&handle.get_bytes(b"json").unwrap().unwrap()[..], json.as_bytes());
+            assert_eq!(&handle.get_bytes(b"pink").unwrap().unwrap()[..], b"red");
+        }
+    }
+
+    #[test]
+    fn filter_turns_away_missing_keys_without_reading_the_index() {
+        let pairs = kvs();
+        let mut config = WriterConfig::default();
+        config.filter(0.01).checksum(true);
+        let mut data = write_cdb_with(&pairs, &config).unwrap();
+
+        let cdb = Reader::new(&data).unwrap();
+        validate(&pairs, &cdb).unwrap();
+        let report = verify::verify(&cdb);
+        assert!(report.is_ok(), "{:?}", report);
+        let filter = cdb.trailer().unwrap().filter().unwrap();
+        assert_eq!(report.index.end, filter.start);
+
+        // with the main table pointing nowhere, only lookups the filter lets
+        // through notice
+        for b in &mut data[..MAIN_TABLE_SIZE_BYTES as usize] {
+            *b = 0xff;
+        }
+        let cdb = Reader::new(&data).unwrap();
+        assert!(cdb.get_ref(pairs[0].0.as_bytes()).is_err());
+        let turned_away = (0..1000)
+            .map(|n| cdb.get_ref(format!("miss{}", n).as_bytes()))
+            .filter(|kv| kv.as_ref().map(|kv| kv.is_none()).unwrap_or(false))
+            .count();
+        assert!(turned_away > 970, "{}", turned_away);
+
+        let config = WriterConfig::default().filter(1.0).clone();
+        let err = write_cdb_with(&pairs, &config).err().unwrap();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::InvalidFalsePositiveRate { .. }) => (),
+            _ => panic!("expected InvalidFalsePositiveRate, got: {:?}", err),
+        }
+    }
 
-            for idx_ent in ordered {
-                buf.put_u32_le(idx_ent.hash.inner());
-                buf.put_u32_le(idx_ent.ptr);
+    #[test]
+    fn get_many_finds_what_get_ref_does() {
+        let mut pairs: Vec<(String, String)> =
+            (0..1000).map(|n| (format!("key{}", n), format!("value{}", n))).collect();
+        pairs.push(("key7".to_string(), "again".to_string()));
+        let mut keys: Vec<String> = (0..1200).map(|n| format!("key{}", n)).collect();
+        keys.extend(keys.clone());
+        keys.push(String::new());
+
+        let mut filtered = WriterConfig::default();
+        filtered.filter(0.01);
+        let mut xxh3 = WriterConfig::default();
+        xxh3.hash_fn(HashFn::xxh3_random()).pointer_width(PointerWidth::U64);
+
+        for config in &[WriterConfig::default(), filtered, xxh3] {
+            let data = write_cdb_with(&pairs, config).unwrap();
+            let cdb = Reader::new(&data).unwrap();
+
+            let found = cdb.get_many(&keys);
+            assert_eq!(found.len(), keys.len());
+            for (key, kv) in keys.iter().zip(found) {
+                let expected = cdb.get_ref(key.as_bytes()).unwrap().map(|kv| kv.v);
+                assert_eq!(kv.unwrap().map(|kv| kv.v), expected, "{}", key);
             }
+            let found = cdb.get_many(&["key7"]);
+            assert_eq!(found[0].as_ref().unwrap().as_ref().unwrap().v, b"value7");
+            assert!(cdb.get_many::<&[u8]>(&[]).is_empty());
+        }
 
-            self.file.write_all(&buf[..])?;
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data[..100]).unwrap();
+        let found = cdb.get_many(&["key1", "key2"]);
+        assert!(found.iter().all(|kv| kv.is_err()));
+    }
+
+    #[test]
+    fn get_all_yields_duplicates_in_insertion_order() {
+        let mut pairs = kvs();
+        for (i, k) in ["abc", "pink", "abc", "abc"].iter().enumerate() {
+            pairs.push((k.to_string(), format!("dup{}", i)));
         }
 
-        // now write the buckets
-        //
-        self.file.seek(SeekFrom::Start(0))?;
-        {
-            let mut buf = ready_buf(2048);
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data).unwrap();
+
+        let vals: Vec<Vec<u8>> = cdb.get_all(b"abc")
+            .unwrap()
+            .map(|kv| kv.unwrap().v.to_vec())
+            .collect();
+
+        assert_eq!(vals, vec![b"def".to_vec(), b"dup0".to_vec(), b"dup2".to_vec(), b"dup3".to_vec()]);
+        assert_eq!(cdb.count(b"abc").unwrap(), 4);
+        assert_eq!(cdb.count(b"pink").unwrap(), 2);
+        assert_eq!(cdb.count(b"q").unwrap(), 1);
+        assert_eq!(cdb.count(b"1233").unwrap(), 0);
 
-            for bkt in buckets {
-                buf.put_u32_le(bkt.ptr);
-                buf.put_u32_le(bkt.num_ents);
+        assert_eq!(cdb.get_ref(b"abc").unwrap().unwrap().v, b"def");
+    }
+
+    #[test]
+    fn get_reports_values_larger_than_the_buffer() {
+        let pairs = kvs();
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data).unwrap();
+
+        let mut buf = vec![0u8; 4];
+        let err = cdb.get(b"apple", &mut buf[..]).unwrap_err();
+
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::ValueTooLarge { max_size, val_size }) => {
+                assert_eq!(*max_size, 4);
+                assert_eq!(*val_size, 5);
+            }
+            _ => panic!("expected ValueTooLarge, got: {:?}", err),
+        }
+        assert_eq!(buf, vec![0u8; 4]);
+
+        // an exact fit is fine
+        assert_eq!(cdb.get(b"burp", &mut buf[..]).unwrap(), None);
+        assert_eq!(cdb.get(b"q", &mut buf[..]).unwrap(), Some(4));
+        assert_eq!(&buf[..], b"burp");
+    }
+
+    #[test]
+    fn lookups_on_damaged_files_return_errors() {
+        let pairs = kvs();
+
+        let check = |data: &[u8]| {
+            let cdb = match Reader::new(data) {
+                Ok(cdb) => cdb,
+                Err(_) => return,
+            };
+            let mut buf = vec![0u8; 10];
+            for (k, _) in &pairs {
+                let _ = cdb.get(k.as_bytes(), &mut buf);
+                let _ = cdb.count(k.as_bytes());
+            }
+            let keys: Vec<&String> = pairs.iter().map(|(k, _)| k).collect();
+            cdb.get_many(&keys);
+            if let Ok(it) = cdb.iter() {
+                for _ in it {}
+            }
+            verify::verify(&cdb);
+        };
+
+        for &width in &[PointerWidth::U32, PointerWidth::U64] {
+            let data = write_cdb_with(&pairs, WriterConfig::default().pointer_width(width)).unwrap();
+
+            // truncated anywhere
+            for n in 0..data.len() {
+                check(&data[..n]);
             }
 
-            self.file.write_all(&buf[..])?;
+            // or with a byte clobbered anywhere
+            for n in 0..data.len() {
+                for &x in &[0x00, 0xff] {
+                    let mut bad = data.clone();
+                    bad[n] = x;
+                    check(&bad);
+                }
+            }
         }
 
-        // start at BOF
-        self.file.seek(SeekFrom::Start(0))?;
+        let data = write_cdb(&pairs).unwrap();
+        let cdb = Reader::new(&data[..100]).unwrap();
+        let err = cdb.get_ref(b"abc").unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::MainTableTruncated { len: 100, .. }) => (),
+            _ => panic!("expected MainTableTruncated, got: {:?}", err),
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn round_trip_boxed_slice_test() {
+        let pairs = kvs();
+
+        load_and_validate_cdb(&pairs, |ntf| {
+            let mut buf = Vec::new();
+            ntf.read_to_end(&mut buf)?;
+            Ok(buf.into_boxed_slice())
+        }).unwrap()
     }
-}
 
-impl<'a, F> Drop for Writer<'a, F>
-    where
-        F: Write + Seek + 'a,
-{
-    fn drop(&mut self) {
-        self.finalize().unwrap();
+    #[test]
+    fn round_trip_heap_backend() {
+        let pairs = kvs();
+
+        load_and_validate_cdb(&pairs, |ntf| {
+            Backend::load(ntf.as_file_mut())
+        }).unwrap()
+    }
+
+    #[test]
+    fn round_trip_mmap_backend() {
+        let pairs = kvs();
+
+        load_and_validate_cdb(&pairs, |ntf| {
+            Backend::mmap(ntf.as_file_mut())
+        }).unwrap()
     }
 }
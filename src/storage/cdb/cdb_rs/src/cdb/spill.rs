@@ -0,0 +1,90 @@
+//! Index entries the writer has moved out of memory, see
+//! `WriterConfig::max_index_memory`.
+//!
+//! Each spill writes every pending entry to an anonymous temp file as a run,
+//! table by table. When the writer finishes, a table's entries are read back
+//! from every run in turn, which keeps them in the order they were put.
+
+use super::{CDBHash, IndexEntry, Result};
+use bytes::{Buf, BufMut, BytesMut, IntoBuf};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use tempfile;
+
+// hash: u32, ptr: u64
+const ENTRY_SIZE: usize = 12;
+
+pub struct Spill {
+    file: File,
+    // for every run, where each table's entries start and how many there are
+    runs: Vec<Vec<(u64, usize)>>,
+    // the number of entries spilled for each table, across all runs
+    spilled: Vec<usize>,
+    end: u64,
+}
+
+impl Spill {
+    /// A spill for an index of `tables` secondary tables.
+    pub fn new(tables: usize) -> Result<Spill> {
+        Ok(Spill {
+            file: tempfile::tempfile()?,
+            runs: Vec::new(),
+            spilled: vec![0; tables],
+            end: 0,
+        })
+    }
+
+    /// The number of entries of `table` that have been spilled.
+    pub fn spilled(&self, table: usize) -> usize {
+        self.spilled[table]
+    }
+
+    /// Writes out `index` as a new run, leaving every table empty.
+    pub fn push_run(&mut self, index: &mut [Vec<IndexEntry>]) -> Result<()> {
+        let mut run = Vec::with_capacity(index.len());
+
+        // reading tables back moves the position around
+        self.file.seek(SeekFrom::Start(self.end))?;
+        let mut w = BufWriter::new(&mut self.file);
+
+        for (n, tbl) in index.iter_mut().enumerate() {
+            let tbl = mem::replace(tbl, Vec::new());
+            let mut buf = BytesMut::with_capacity(tbl.len() * ENTRY_SIZE);
+            for ie in &tbl {
+                buf.put_u32_le(ie.hash.inner());
+                buf.put_u64_le(ie.ptr);
+            }
+            w.write_all(&buf[..])?;
+
+            run.push((self.end, tbl.len()));
+            self.spilled[n] += tbl.len();
+            self.end += buf.len() as u64;
+        }
+
+        w.flush()?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Reads back every entry spilled for `table`, in the order they were put.
+    pub fn table(&mut self, table: usize) -> Result<Vec<IndexEntry>> {
+        let mut entries = Vec::with_capacity(self.spilled[table]);
+
+        for run in &self.runs {
+            let (pos, count) = run[table];
+            let mut buf = vec![0u8; count * ENTRY_SIZE];
+            self.file.seek(SeekFrom::Start(pos))?;
+            self.file.read_exact(&mut buf)?;
+
+            let mut b = buf.into_buf();
+            for _ in 0..count {
+                let hash = CDBHash(b.get_u32_le());
+                let ptr = b.get_u64_le();
+                entries.push(IndexEntry { hash, ptr });
+            }
+        }
+
+        Ok(entries)
+    }
+}
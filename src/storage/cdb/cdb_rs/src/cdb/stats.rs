@@ -0,0 +1,75 @@
+//! Numbers describing the shape of a finished build, see `BuildSummary`.
+
+use std::cmp;
+
+/// The smallest, largest and mean size of a set of keys or values, in bytes.
+/// All zero for an empty set.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SizeStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BuildStats {
+    /// The size of the data segment in bytes.
+    pub data_bytes: u64,
+    /// The size of the secondary tables in bytes.
+    pub index_bytes: u64,
+    /// The sizes of the keys of every record written to the data segment.
+    /// For `Writer::append` that includes the records the file already held.
+    pub key_sizes: SizeStats,
+    /// The sizes of the values of the same records as `key_sizes`, as they
+    /// were put rather than as they're stored.
+    pub value_sizes: SizeStats,
+    /// The number of records in each secondary table, in main table order.
+    pub table_entries: Vec<u64>,
+    /// How many slots past its home slot the furthest entry sits, across all
+    /// the secondary tables. A lookup probes this many extra slots at worst.
+    pub max_probe: u64,
+    /// How many slots past its home slot the average entry sits.
+    pub mean_probe: f64,
+    /// The number of times a key that had already been put was put again,
+    /// rejected and dropped records included. None under
+    /// `DuplicateKeys::Allow`, which doesn't keep track of keys, unless
+    /// `WriterConfig::count_duplicates` is on.
+    pub duplicates: Option<usize>,
+    /// The number of values stored compressed, see `WriterConfig::compression`.
+    pub compressed: usize,
+}
+
+/// Running totals behind a `SizeStats`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sizes {
+    count: u64,
+    min: u64,
+    max: u64,
+    total: u64,
+}
+
+impl Sizes {
+    pub fn add(&mut self, size: usize) {
+        let size = size as u64;
+        self.min = if self.count == 0 { size } else { cmp::min(self.min, size) };
+        self.max = cmp::max(self.max, size);
+        self.total += size;
+        self.count += 1;
+    }
+
+    pub fn stats(&self) -> SizeStats {
+        SizeStats {
+            min: self.min,
+            max: self.max,
+            mean: mean(self.total, self.count),
+        }
+    }
+}
+
+pub fn mean(total: u64, count: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    }
+}
@@ -0,0 +1,94 @@
+//! Building a cdb file onto an output that can't seek, like a pipe, stdout
+//! or a compressor.
+//!
+//! A cdb file's main table comes first but can only be written once every
+//! record is in, so `StreamWriter` builds the file in an anonymous spool file
+//! (in `TMPDIR`) and copies it out front to back when it's finished. The
+//! output gets exactly the bytes `Writer` would have written.
+
+use super::writer::{BuildSummary, Writer, WriterConfig};
+use super::Result;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use tempfile;
+
+pub struct StreamWriter<W: Write> {
+    writer: Writer<BufWriter<File>>,
+    spool: File,
+    out: W,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(out: W) -> Result<StreamWriter<W>> {
+        StreamWriter::with_config(out, &WriterConfig::default())
+    }
+
+    pub fn with_config(out: W, config: &WriterConfig) -> Result<StreamWriter<W>> {
+        let spool = tempfile::tempfile()?;
+        let writer = Writer::with_config(BufWriter::new(spool.try_clone()?), config)?;
+        Ok(StreamWriter { writer, spool, out })
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writer.put(key, value)
+    }
+
+    pub fn put_with_hint(&mut self, key: &[u8], value: &[u8], hint: u64) -> Result<()> {
+        self.writer.put_with_hint(key, value, hint)
+    }
+
+    /// Finishes the file and copies it to the output, which is flushed. Pass
+    /// in a `&mut` to get the output back afterwards, e.g. to finish a
+    /// compressor.
+    pub fn finish(self) -> Result<BuildSummary> {
+        let StreamWriter { writer, mut spool, mut out } = self;
+
+        let summary = writer.finish()?;
+        spool.seek(SeekFrom::Start(0))?;
+        io::copy(&mut spool, &mut out)?;
+        out.flush()?;
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdb::{HashFn, PointerWidth, Reader};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn streams_the_same_bytes_as_writer() {
+        let mut config = WriterConfig::default();
+        config
+            .checksum(true)
+            .pointer_width(PointerWidth::U64)
+            .hash_fn(HashFn::Xxh3 { seed: 7 });
+
+        let mut expected = Vec::new();
+        {
+            let mut cur = Cursor::new(Vec::new());
+            {
+                let mut w = Writer::with_config(&mut cur, &config).unwrap();
+                w.put(b"abc", b"def").unwrap();
+                w.put(b"pink", b"red").unwrap();
+                w.finish().unwrap();
+            }
+            cur.read_to_end(&mut expected).unwrap();
+        }
+
+        // a Vec can be written to, but not seeked
+        let mut out = Vec::new();
+        let summary = {
+            let mut w = StreamWriter::with_config(&mut out, &config).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            w.put(b"pink", b"red").unwrap();
+            w.finish().unwrap()
+        };
+
+        assert_eq!(summary.len, out.len() as u64);
+        assert!(out == expected);
+        assert_eq!(Reader::new(&out).unwrap().get_ref(b"pink").unwrap().unwrap().v, b"red");
+    }
+}
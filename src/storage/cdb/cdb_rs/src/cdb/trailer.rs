@@ -0,0 +1,535 @@
+//! An optional trailer written after the secondary tables, describing which
+//! variant of the format the file is in.
+//!
+//! Readers of the original format only ever follow pointers out of the main
+//! table, so they never look at the trailer. Files that stick to what djb cdb
+//! supports (32 bit pointers, the djb hash) stay readable by any
+//! implementation whether or not they carry one.
+//!
+//! The trailer ends in a fixed 16 byte suffix so it can be found from the end
+//! of the file:
+//!
+//! ```text
+//! +-------------------+-----------+---------------+----------------+
+//! | body (len - 16 B) | len: u32  | version: u32  | magic: [u8; 8] |
+//! +-------------------+-----------+---------------+----------------+
+//! ```
+//!
+//! `len` is the size of the whole trailer. Fields are only ever appended to
+//! the body, so an older trailer is just a shorter one, and the fields it
+//! lacks take their default values. The body holds, in order:
+//!
+//! * `flags: u32` - which optional features the file uses, see `FLAG_*`
+//! * `checksum: u64` - checksum of the file, if `FLAG_CHECKSUM` is set
+//! * `pointer_width: u8` - 4 for djb cdb files, 8 for cdb64 files. Files
+//!   without a trailer, or with one that predates this field, are djb files.
+//! * `hash_fn: u8` - the id of the function keys are hashed with, see `HashFn`.
+//!   Defaults to djb's hash.
+//! * `hash_seed: u64` - the seed for hash functions that take one
+//! * `main_table_bits: u8` - the main table has `2^main_table_bits` buckets.
+//!   Defaults to 8, djb's 256 buckets. Anything else has `FLAG_MAIN_TABLE`
+//!   set too, so readers that predate this field refuse the file.
+//! * `dictionary_offset: u64`, `dictionary_len: u32` - where the zstd
+//!   dictionary values were compressed against sits, if `FLAG_DICTIONARY` is
+//!   set. It goes between the secondary tables and the trailer.
+//! * `filter_offset: u64`, `filter_len: u64` - where the filter over the
+//!   file's keys sits, if `FLAG_FILTER` is set. It goes after the dictionary,
+//!   if there is one, and before the trailer.
+//!
+//! A reader refuses files with flags set that it doesn't know about, or a
+//! version, pointer width or hash function it doesn't support, rather than
+//! returning garbage from a file it can't read.
+//!
+//! All integers are little-endian.
+//!
+//! The checksum is xxh3 (64 bit) of the whole file but the checksum field
+//! itself, with the main table fed in *last*:
+//! `xxh3(file[main..trailer] ++ trailer[..4] ++ trailer[12..] ++ file[..main])`,
+//! where `main` is the size of the main table (2048 bytes for a djb file) and
+//! `trailer` is the trailer's bytes. The main table is the last thing the
+//! writer knows, and this order lets it compute the checksum in a single pass
+//! as it writes. Covering the trailer means a damaged flag, hash seed or
+//! offset is caught, rather than misreading an otherwise intact file.
+
+use super::{CDBError, HashFn, MainTable, PointerWidth, Result};
+use bytes::{Buf, BufMut, BytesMut, IntoBuf};
+use std::hash::Hasher;
+use std::ops::Range;
+use twox_hash::xxh3;
+
+pub const MAGIC: &[u8; 8] = b"cdbtrlr\0";
+pub const VERSION: u32 = 1;
+
+/// The file's trailer carries a checksum.
+pub const FLAG_CHECKSUM: u32 = 1;
+
+/// The file's main table isn't the usual 256 buckets.
+pub const FLAG_MAIN_TABLE: u32 = 2;
+
+/// Every value in the file starts with a codec tag, see `Compression`.
+pub const FLAG_COMPRESSED: u32 = 4;
+
+/// The file carries a zstd dictionary, see `Compression::ZstdDictionary`.
+pub const FLAG_DICTIONARY: u32 = 8;
+
+/// The file carries a filter over its keys, see `WriterConfig::filter`.
+pub const FLAG_FILTER: u32 = 16;
+
+/// Every flag this version knows how to handle.
+pub const KNOWN_FLAGS: u32 =
+    FLAG_CHECKSUM | FLAG_MAIN_TABLE | FLAG_COMPRESSED | FLAG_DICTIONARY | FLAG_FILTER;
+
+const SUFFIX_SIZE: usize = 16;
+
+// where the checksum sits in an encoded trailer, after the flags
+const CHECKSUM_FIELD: Range<usize> = 4..12;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Trailer {
+    pub flags: u32,
+    pub checksum: u64,
+    pub pointer_width: PointerWidth,
+    pub hash_fn: HashFn,
+    pub main_table: MainTable,
+    pub dictionary_offset: u64,
+    pub dictionary_len: u32,
+    pub filter_offset: u64,
+    pub filter_len: u64,
+}
+
+impl Trailer {
+    pub fn has_checksum(&self) -> bool {
+        self.flags & FLAG_CHECKSUM != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    /// Where the file's dictionary is, if it has one.
+    pub fn dictionary(&self) -> Option<Range<usize>> {
+        if self.flags & FLAG_DICTIONARY == 0 {
+            return None;
+        }
+        let start = self.dictionary_offset as usize;
+        Some(start..start.saturating_add(self.dictionary_len as usize))
+    }
+
+    /// Where the file's filter is, if it has one.
+    pub fn filter(&self) -> Option<Range<usize>> {
+        if self.flags & FLAG_FILTER == 0 {
+            return None;
+        }
+        let start = self.filter_offset as usize;
+        Some(start..start.saturating_add(self.filter_len as usize))
+    }
+
+    /// The size in bytes of the main table of the file the trailer describes.
+    pub fn main_table_size(&self) -> usize {
+        self.main_table.size(self.pointer_width)
+    }
+
+    /// Looks for a trailer at the end of `data`. Returns the offset the trailer
+    /// starts at along with its contents, or None if there isn't one, as is the
+    /// case for plain djb cdb files.
+    pub fn find(data: &[u8]) -> Result<Option<(usize, Trailer)>> {
+        let smallest = MainTable::default().size(PointerWidth::U32);
+        let min_len = smallest + SUFFIX_SIZE;
+        if data.len() < min_len || &data[(data.len() - MAGIC.len())..] != MAGIC {
+            return Ok(None);
+        }
+
+        let mut suffix = data[(data.len() - SUFFIX_SIZE)..].into_buf();
+        let len = suffix.get_u32_le() as usize;
+        let version = suffix.get_u32_le();
+
+        if version != VERSION {
+            return Err(CDBError::UnsupportedTrailerVersion { version }.into());
+        }
+
+        let out_of_range = CDBError::TrailerOutOfRange { len, file_len: data.len() };
+        if len < SUFFIX_SIZE || len > data.len() - smallest {
+            return Err(out_of_range.into());
+        }
+
+        let start = data.len() - len;
+        let mut body = data[start..(data.len() - SUFFIX_SIZE)].into_buf();
+        let mut trailer = Trailer::default();
+
+        if body.remaining() >= 4 {
+            trailer.flags = body.get_u32_le();
+        }
+        if body.remaining() >= 8 {
+            trailer.checksum = body.get_u64_le();
+        }
+        if body.remaining() >= 1 {
+            trailer.pointer_width = PointerWidth::from_bytes(body.get_u8())?;
+        }
+        let hash_id = if body.remaining() >= 1 { body.get_u8() } else { 0 };
+        let hash_seed = if body.remaining() >= 8 { body.get_u64_le() } else { 0 };
+        trailer.hash_fn = HashFn::from_id(hash_id, hash_seed)?;
+        if body.remaining() >= 1 {
+            trailer.main_table = MainTable::with_bits(body.get_u8())?;
+        }
+        if body.remaining() >= 12 {
+            trailer.dictionary_offset = body.get_u64_le();
+            trailer.dictionary_len = body.get_u32_le();
+        }
+        if body.remaining() >= 16 {
+            trailer.filter_offset = body.get_u64_le();
+            trailer.filter_len = body.get_u64_le();
+        }
+
+        if trailer.flags & !KNOWN_FLAGS != 0 {
+            return Err(CDBError::UnsupportedFeatures { flags: trailer.flags & !KNOWN_FLAGS }.into());
+        }
+
+        if start < trailer.main_table_size() {
+            return Err(out_of_range.into());
+        }
+
+        let valid_range = trailer.main_table_size()..start;
+        if let Some(dictionary) = trailer.dictionary() {
+            if dictionary.start < valid_range.start || dictionary.end > valid_range.end {
+                let valid_range = valid_range.clone();
+                return Err(CDBError::DictionaryOutOfRange { valid_range, dictionary }.into());
+            }
+        }
+        if let Some(filter) = trailer.filter() {
+            if filter.start < valid_range.start || filter.end > valid_range.end {
+                return Err(CDBError::FilterOutOfRange { valid_range, filter }.into());
+            }
+        }
+
+        Ok(Some((start, trailer)))
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        let len = 4 + 8 + 1 + 1 + 8 + 1 + 8 + 4 + 8 + 8 + SUFFIX_SIZE;
+        let mut buf = BytesMut::with_capacity(len);
+
+        buf.put_u32_le(self.flags);
+        buf.put_u64_le(self.checksum);
+        buf.put_u8(self.pointer_width.bytes() as u8);
+        buf.put_u8(self.hash_fn.id());
+        buf.put_u64_le(self.hash_fn.seed());
+        buf.put_u8(self.main_table.bits());
+        buf.put_u64_le(self.dictionary_offset);
+        buf.put_u32_le(self.dictionary_len);
+        buf.put_u64_le(self.filter_offset);
+        buf.put_u64_le(self.filter_len);
+
+        buf.put_u32_le(len as u32);
+        buf.put_u32_le(VERSION);
+        buf.extend_from_slice(MAGIC);
+        buf
+    }
+}
+
+/// Computes the checksum of a file as it's written. See the module docs for
+/// the order the file has to be fed in.
+pub struct Checksum(xxh3::Hash64);
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum(xxh3::Hash64::with_seed(0))
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    /// Takes in an encoded trailer, all but its checksum field.
+    pub fn update_trailer(&mut self, trailer: &[u8]) {
+        self.update(&trailer[..CHECKSUM_FIELD.start]);
+        self.update(&trailer[CHECKSUM_FIELD.end..]);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum::new()
+    }
+}
+
+/// Computes the checksum of `data`, whose trailer starts at `trailer_start`
+/// and whose main table is `main_table` bytes long.
+pub fn checksum(data: &[u8], trailer_start: usize, main_table: usize) -> u64 {
+    let mut ck = Checksum::new();
+    ck.update(&data[main_table..trailer_start]);
+    ck.update_trailer(&data[trailer_start..]);
+    ck.update(&data[..main_table]);
+    ck.finish()
+}
+
+/// Checks `data` against the checksum in its trailer. It's an error for the
+/// file not to have one.
+pub fn verify_checksum(data: &[u8]) -> Result<()> {
+    match Trailer::find(data)? {
+        Some((start, ref t)) if t.has_checksum() => {
+            let actual = checksum(data, start, t.main_table_size());
+            if actual == t.checksum {
+                Ok(())
+            } else {
+                Err(CDBError::ChecksumMismatch { expected: t.checksum, actual }.into())
+            }
+        }
+        _ => Err(CDBError::MissingChecksum.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // appends 't' to 'data', with the checksum of the two
+    fn append_checksummed(data: &mut Vec<u8>, mut t: Trailer) {
+        let start = data.len();
+        t.flags |= FLAG_CHECKSUM;
+        data.extend_from_slice(&t.encode());
+        t.checksum = checksum(data, start, 2048);
+        data.truncate(start);
+        data.extend_from_slice(&t.encode());
+    }
+
+    fn file_with_trailer() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..3000u32).map(|n| n as u8).collect();
+        append_checksummed(&mut data, Trailer::default());
+        data
+    }
+
+    #[test]
+    fn find_round_trip() {
+        let data = file_with_trailer();
+        let (start, t) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(start, 3000);
+        assert!(t.has_checksum());
+        assert_eq!(t.checksum, checksum(&data, 3000, 2048));
+        verify_checksum(&data).unwrap();
+        assert_eq!(t.pointer_width, PointerWidth::U32);
+        assert_eq!(t.hash_fn, HashFn::Djb);
+        assert_eq!(t.main_table, MainTable::default());
+
+        let mut data = vec![0u8; 3000];
+        let t = Trailer {
+            hash_fn: HashFn::Xxh3 { seed: 0x0123_4567_89ab_cdef },
+            ..Trailer::default()
+        };
+        data.extend_from_slice(&t.encode());
+        assert_eq!(Trailer::find(&data).unwrap(), Some((3000, t)));
+
+        // 512 buckets take up 4096 bytes
+        let mut data = vec![0u8; 3000];
+        let t = Trailer {
+            flags: FLAG_MAIN_TABLE,
+            main_table: MainTable::with_bits(9).unwrap(),
+            ..Trailer::default()
+        };
+        data.extend_from_slice(&t.encode());
+        assert!(Trailer::find(&data).is_err());
+
+        let mut data = vec![0u8; 5000];
+        data.extend_from_slice(&t.encode());
+        assert_eq!(Trailer::find(&data).unwrap(), Some((5000, t)));
+    }
+
+    #[test]
+    fn older_trailers_default_missing_fields() {
+        let mut data = vec![0u8; 3000];
+        // a trailer with nothing after the flags
+        data.extend_from_slice(&[1, 0, 0, 0]);
+        data.extend_from_slice(&[20, 0, 0, 0, 1, 0, 0, 0]);
+        data.extend_from_slice(MAGIC);
+
+        let (start, t) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(start, 3000);
+        assert_eq!(t.flags, FLAG_CHECKSUM);
+        assert_eq!(t.checksum, 0);
+        assert_eq!(t.pointer_width, PointerWidth::U32);
+        assert_eq!(t.main_table, MainTable::default());
+    }
+
+    #[test]
+    fn bad_pointer_width() {
+        let mut data = file_with_trailer();
+        let n = data.len();
+        data[n - 55] = 5;
+        assert!(Trailer::find(&data).is_err());
+    }
+
+    #[test]
+    fn bad_main_table() {
+        let mut data = file_with_trailer();
+        let n = data.len();
+        data[n - 45] = 17;
+
+        let err = Trailer::find(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::UnsupportedMainTable { bits: 17 }) => (),
+            _ => panic!("expected UnsupportedMainTable, got: {:?}", err),
+        }
+
+        // a main table that doesn't fit before the trailer
+        data[n - 45] = 16;
+        assert!(Trailer::find(&data).is_err());
+    }
+
+    #[test]
+    fn dictionary_sits_between_the_main_table_and_the_trailer() {
+        let mut t = Trailer {
+            flags: FLAG_DICTIONARY,
+            dictionary_offset: 2500,
+            dictionary_len: 500,
+            ..Trailer::default()
+        };
+        let mut data = vec![0u8; 3000];
+        data.extend_from_slice(&t.encode());
+        let (_, found) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(found.dictionary(), Some(2500..3000));
+
+        for &(offset, len) in &[(2500, 501), (2000, 100)] {
+            t.dictionary_offset = offset;
+            t.dictionary_len = len;
+            let mut data = vec![0u8; 3000];
+            data.extend_from_slice(&t.encode());
+
+            let err = Trailer::find(&data).unwrap_err();
+            match err.downcast_ref::<CDBError>() {
+                Some(CDBError::DictionaryOutOfRange { .. }) => (),
+                _ => panic!("expected DictionaryOutOfRange, got: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn filter_sits_between_the_main_table_and_the_trailer() {
+        let mut t = Trailer {
+            flags: FLAG_FILTER,
+            filter_offset: 2100,
+            filter_len: 900,
+            ..Trailer::default()
+        };
+        let mut data = vec![0u8; 3000];
+        data.extend_from_slice(&t.encode());
+        let (_, found) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(found.filter(), Some(2100..3000));
+        assert_eq!(found.dictionary(), None);
+
+        for &(offset, len) in &[(2100, 901), (2000, 100), (2100, u64::max_value())] {
+            t.filter_offset = offset;
+            t.filter_len = len;
+            let mut data = vec![0u8; 3000];
+            data.extend_from_slice(&t.encode());
+
+            let err = Trailer::find(&data).unwrap_err();
+            match err.downcast_ref::<CDBError>() {
+                Some(CDBError::FilterOutOfRange { .. }) => (),
+                _ => panic!("expected FilterOutOfRange, got: {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_hash_fn() {
+        let mut data = file_with_trailer();
+        let n = data.len();
+        data[n - 54] = 0xee;
+
+        let err = Trailer::find(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::UnsupportedHashFn { id: 0xee }) => (),
+            _ => panic!("expected UnsupportedHashFn, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn unknown_flags() {
+        let mut data = file_with_trailer();
+        let start = data.len() - Trailer::default().encode().len();
+        data[start + 3] |= 0x80;
+
+        let err = Trailer::find(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::UnsupportedFeatures { flags: 0x8000_0000 }) => (),
+            _ => panic!("expected UnsupportedFeatures, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut data = file_with_trailer();
+        let n = data.len();
+        data[n - 12] = 2;
+
+        let err = Trailer::find(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::UnsupportedTrailerVersion { version: 2 }) => (),
+            _ => panic!("expected UnsupportedTrailerVersion, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn no_trailer() {
+        let data = vec![0u8; 3000];
+        assert!(Trailer::find(&data).unwrap().is_none());
+        assert!(Trailer::find(&data[..10]).unwrap().is_none());
+
+        let err = verify_checksum(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::MissingChecksum) => (),
+            _ => panic!("expected MissingChecksum, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn bad_trailer_length() {
+        let mut data = file_with_trailer();
+        let n = data.len();
+        data[n - 16] = 0xff;
+        data[n - 15] = 0xff;
+        assert!(Trailer::find(&data).is_err());
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let mut data = file_with_trailer();
+        verify_checksum(&data).unwrap();
+
+        for &pos in &[0, 2047, 2048, 2999] {
+            data[pos] ^= 1;
+            let err = verify_checksum(&data).unwrap_err();
+            match err.downcast_ref::<CDBError>() {
+                Some(CDBError::ChecksumMismatch { .. }) => (),
+                _ => panic!("expected ChecksumMismatch, got: {:?}", err),
+            }
+            data[pos] ^= 1;
+        }
+    }
+
+    #[test]
+    fn verify_detects_a_damaged_trailer() {
+        let t = Trailer {
+            hash_fn: HashFn::Xxh3 { seed: 0x0123_4567_89ab_cdef },
+            ..Trailer::default()
+        };
+        let mut data = vec![0u8; 3000];
+        append_checksummed(&mut data, t);
+        verify_checksum(&data).unwrap();
+
+        // the low byte of the hash seed, which still makes a valid trailer
+        let n = data.len();
+        data[n - 53] ^= 1;
+        let (_, found) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(found.hash_fn, HashFn::Xxh3 { seed: 0x0123_4567_89ab_cdee });
+
+        let err = verify_checksum(&data).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::ChecksumMismatch { .. }) => (),
+            _ => panic!("expected ChecksumMismatch, got: {:?}", err),
+        }
+    }
+}
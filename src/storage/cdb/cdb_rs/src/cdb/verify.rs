@@ -0,0 +1,459 @@
+//! Structural checks for cdb files, meant to be run over a file before it's
+//! put into service.
+//!
+//! `verify` walks every bucket of the main table and every entry of every
+//! secondary table, and checks that:
+//!
+//! * each secondary table falls inside the index region
+//! * each data pointer points into the data segment
+//! * each record fits inside the data segment
+//! * each record's key hashes back to the bucket and slot it was found in,
+//!   so a lookup for that key can actually reach it
+//! * each value of a compressed file decompresses
+//! * the file's filter, if it has one, holds each key
+//! * the file matches the checksum in its trailer, if it has one
+//!
+//! Everything found is collected into a `Report` rather than stopping at the
+//! first problem.
+
+use super::compress::{self, Dictionary};
+use super::trailer::{self, Trailer};
+use super::{Bucket, CDBError, CDBHash, Reader};
+use bytes::IntoBuf;
+use failure;
+use std::ops::Range;
+
+/// Something wrong with a cdb file, and where it was found.
+#[derive(Debug)]
+pub struct Problem {
+    /// The main table bucket whose secondary table the problem was found in.
+    pub bucket: Option<usize>,
+    /// The slot of the secondary table the problem was found in.
+    pub slot: Option<usize>,
+    pub error: CDBError,
+}
+
+/// The result of running `verify` over a file.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The region between the main table and the first secondary table.
+    pub data_segment: Range<usize>,
+    /// The region holding the secondary tables.
+    pub index: Range<usize>,
+    /// The number of non-empty index entries that were checked.
+    pub entries: usize,
+    /// The file's trailer, if it has one.
+    pub trailer: Option<Trailer>,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, bucket: Option<usize>, slot: Option<usize>, error: CDBError) {
+        self.problems.push(Problem { bucket, slot, error });
+    }
+}
+
+/// Checks the structure of the cdb file behind `reader`, see the module docs
+/// for what's covered.
+pub fn verify(reader: &Reader) -> Report {
+    // rather than preparing the dictionary again for every value
+    let dictionary = reader.dictionary().map(Dictionary::new);
+    let reader = &match dictionary {
+        Some(ref dictionary) => reader.with_prepared(dictionary),
+        None => *reader,
+    };
+
+    let mut report = Report::default();
+    let len = reader.len();
+    let main_table = reader.main_table_size();
+
+    if len < main_table {
+        report.push(None, None, CDBError::MainTableTruncated { len, min_len: main_table });
+        return report;
+    }
+
+    // if there's a trailer, the index stops where it starts, or where the
+    // dictionary or the filter does if the file has them
+    let index_end = match Trailer::find(reader) {
+        Ok(Some((start, t))) => {
+            report.trailer = Some(t);
+            if t.has_checksum() {
+                let actual = trailer::checksum(reader, start, t.main_table_size());
+                if actual != t.checksum {
+                    report.push(None, None, CDBError::ChecksumMismatch { expected: t.checksum, actual });
+                }
+            }
+            if t.filter().is_some() && reader.filter.is_none() {
+                let reason = "it has no bits, or picks none for each key".to_owned();
+                report.push(None, None, CDBError::CorruptFilter { reason });
+            }
+            let dictionary = t.dictionary().map(|d| d.start).unwrap_or(start);
+            t.filter().map(|f| f.start).unwrap_or(start).min(dictionary)
+        }
+        Ok(None) => len,
+        Err(err) => {
+            report.push(None, None, problem(err));
+            return report;
+        }
+    };
+
+    // the secondary tables are written out in bucket order after all the
+    // records, so the first bucket marks where the data segment ends
+    let index_start = reader.bucket_at(0).map(|b| b.ptr as usize).unwrap_or(0);
+    if index_start < main_table || index_start > index_end {
+        report.push(
+            Some(0),
+            None,
+            CDBError::TableOutOfIndex {
+                valid_range: main_table..index_end,
+                table: index_start..index_start,
+            },
+        );
+        return report;
+    }
+
+    report.data_segment = main_table..index_start;
+    report.index = index_start..index_end;
+
+    for idx in 0..reader.main_table.buckets() {
+        match reader.bucket_at(idx) {
+            Ok(bucket) => verify_bucket(reader, idx, bucket, &mut report),
+            Err(err) => report.push(Some(idx), None, problem(err)),
+        }
+    }
+
+    report
+}
+
+// the CDBError behind 'err', or one carrying its message if there isn't one
+fn problem(err: failure::Error) -> CDBError {
+    err.downcast::<CDBError>()
+        .unwrap_or_else(|err| CDBError::Unreadable { reason: err.to_string() })
+}
+
+fn verify_bucket(reader: &Reader, idx: usize, bucket: Bucket, report: &mut Report) {
+    let num_ents = bucket.num_ents as usize;
+    let start = bucket.ptr as usize;
+    let end = num_ents
+        .checked_mul(reader.width.pair_size())
+        .and_then(|n| n.checked_add(start))
+        .unwrap_or(usize::max_value());
+
+    if start < report.index.start || end > report.index.end {
+        let valid_range = report.index.clone();
+        report.push(Some(idx), None, CDBError::TableOutOfIndex { valid_range, table: start..end });
+        return;
+    }
+
+    let entries: Vec<_> = (0..bucket.num_ents)
+        .map(|n| match reader.index_entry_at(bucket.entry_n_pos(n, reader.width)) {
+            Ok(ie) => Some(ie),
+            Err(err) => {
+                report.push(Some(idx), Some(n as usize), problem(err));
+                None
+            }
+        })
+        .collect();
+
+    for (slot, ie) in entries.iter().enumerate() {
+        let ie = match *ie {
+            Some(ref ie) => ie,
+            None => continue,
+        };
+
+        if ie.ptr == 0 {
+            continue;
+        }
+
+        report.entries += 1;
+
+        if let Err(err) = verify_entry(reader, &report.data_segment, ie.hash, ie.ptr as usize) {
+            report.push(Some(idx), Some(slot), err);
+            continue;
+        }
+
+        // a lookup starts at the hash's home slot and gives up at the first
+        // empty entry, so there mustn't be one between home and here
+        let home = reader.main_table.slot(ie.hash, num_ents);
+        let home_bucket = reader.main_table.table(ie.hash);
+        let reachable = home_bucket == idx
+            && (0..num_ents)
+                .map(|i| (home + i) % num_ents)
+                .take_while(|&n| n != slot)
+                .all(|n| entries[n].as_ref().map(|e| e.ptr != 0).unwrap_or(false));
+
+        if !reachable {
+            report.push(
+                Some(idx),
+                Some(slot),
+                CDBError::MisplacedIndexEntry {
+                    bucket: idx,
+                    slot,
+                    home_bucket,
+                    home_slot: home,
+                },
+            );
+        }
+    }
+}
+
+fn verify_entry(
+    reader: &Reader,
+    data_segment: &Range<usize>,
+    hash: CDBHash,
+    ptr: usize,
+) -> Result<(), CDBError> {
+    if ptr < data_segment.start || ptr >= data_segment.end {
+        return Err(CDBError::IndexOutOfDataSegment {
+            valid_range: data_segment.clone(),
+            ptr_val: ptr,
+        });
+    }
+
+    let width = reader.width;
+    let header_end = ptr + width.pair_size();
+
+    if header_end > data_segment.end {
+        return Err(CDBError::RecordOverrun {
+            valid_range: data_segment.clone(),
+            record: ptr..header_end,
+        });
+    }
+
+    let (ksize, vsize) = {
+        let mut b = reader[ptr..header_end].into_buf();
+        (width.get(&mut b) as usize, width.get(&mut b) as usize)
+    };
+
+    let record_end = header_end.saturating_add(ksize).saturating_add(vsize);
+    if record_end > data_segment.end {
+        return Err(CDBError::RecordOverrun {
+            valid_range: data_segment.clone(),
+            record: ptr..record_end,
+        });
+    }
+
+    let key = &reader[header_end..(header_end + ksize)];
+    let actual = reader.hash_fn.hash(key);
+    if actual != hash {
+        return Err(CDBError::HashMismatch {
+            indexed: hash.inner(),
+            actual: actual.inner(),
+        });
+    }
+
+    if !reader.filter.map(|f| f.may_contain(key)).unwrap_or(true) {
+        return Err(CDBError::filter_misses_key(key));
+    }
+
+    if reader.is_compressed() {
+        compress::decode(&reader[(header_end + ksize)..record_end], reader.dictionary)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Buf, BufMut};
+    use cdb::{MainTable, PointerWidth, Writer, WriterConfig, MAIN_TABLE_SIZE_BYTES};
+    use std::io::Cursor;
+
+    fn cdb_of(kvs: &[(&str, &str)]) -> Vec<u8> {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::new(&mut cur).unwrap();
+            for (k, v) in kvs {
+                w.put(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            w.finish().unwrap();
+        }
+        cur.into_inner()
+    }
+
+    fn cdb() -> Vec<u8> {
+        cdb_of(&[("abc", "def"), ("pink", "red"), ("apple", "grape"), ("q", "burp")])
+    }
+
+    fn put_u32(data: &mut [u8], pos: usize, n: u32) {
+        Cursor::new(&mut data[pos..(pos + 4)]).put_u32_le(n);
+    }
+
+    fn get_u32(data: &[u8], pos: usize) -> u32 {
+        data[pos..(pos + 4)].into_buf().get_u32_le()
+    }
+
+    // returns the secondary table holding the first record, "abc"
+    fn first_table(data: &[u8]) -> Range<usize> {
+        let b = MainTable::default().table(CDBHash::new(b"abc")) * 8;
+        let ptr = get_u32(data, b) as usize;
+        ptr..(ptr + get_u32(data, b + 4) as usize * 8)
+    }
+
+    // returns the position of the index entry pointing at the first record
+    fn first_entry_pos(data: &[u8]) -> usize {
+        first_table(data)
+            .step_by(8)
+            .find(|&p| get_u32(data, p + 4) == MAIN_TABLE_SIZE_BYTES)
+            .unwrap()
+    }
+
+    fn sole_problem(data: &[u8]) -> CDBError {
+        let mut report = verify(&Reader::new(&data).unwrap());
+        assert_eq!(report.problems.len(), 1, "{:?}", report);
+        report.problems.pop().unwrap().error
+    }
+
+    #[test]
+    fn clean_file_verifies() {
+        let data = cdb();
+        let report = verify(&Reader::new(&data).unwrap());
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.data_segment.start, MAIN_TABLE_SIZE_BYTES as usize);
+    }
+
+    #[test]
+    fn checksummed_file_verifies() {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::with_config(&mut cur, WriterConfig::default().checksum(true)).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            w.finish().unwrap();
+        }
+        let mut data = cur.into_inner();
+
+        let report = verify(&Reader::new(&data).unwrap());
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.index.end, Trailer::find(&data).unwrap().unwrap().0);
+
+        data[MAIN_TABLE_SIZE_BYTES as usize + 12] = b'x';
+        match sole_problem(&data) {
+            CDBError::ChecksumMismatch { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn truncated_main_table() {
+        let data = cdb();
+        match sole_problem(&data[..100]) {
+            CDBError::MainTableTruncated { len: 100, .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn table_outside_index() {
+        let mut data = cdb();
+        let len = data.len() as u32;
+        put_u32(&mut data, 8 * 7, len);
+        put_u32(&mut data, 8 * 7 + 4, 2);
+
+        match sole_problem(&data) {
+            CDBError::TableOutOfIndex { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn data_pointer_outside_data_segment() {
+        let mut data = cdb();
+        let pos = first_entry_pos(&data);
+        put_u32(&mut data, pos + 4, 16);
+
+        match sole_problem(&data) {
+            CDBError::IndexOutOfDataSegment { ptr_val: 16, .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn wild_pointer_in_a_cdb64_file() {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let config = WriterConfig::default().pointer_width(PointerWidth::U64).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            w.finish().unwrap();
+        }
+        let mut data = cur.into_inner();
+
+        let main_table = MainTable::default().size(PointerWidth::U64);
+        let b = MainTable::default().table(CDBHash::new(b"abc")) * 16;
+        let table = data[b..(b + 8)].into_buf().get_u64_le() as usize;
+        let pos = (table..data.len())
+            .step_by(16)
+            .find(|&p| data[(p + 8)..(p + 16)].into_buf().get_u64_le() == main_table as u64)
+            .unwrap();
+        for x in &mut data[(pos + 8)..(pos + 16)] {
+            *x = 0xff;
+        }
+
+        match sole_problem(&data) {
+            CDBError::IndexOutOfDataSegment { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn record_overruns_data_segment() {
+        let mut data = cdb();
+        put_u32(&mut data, MAIN_TABLE_SIZE_BYTES as usize + 4, 1000);
+
+        match sole_problem(&data) {
+            CDBError::RecordOverrun { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn key_does_not_hash_to_entry() {
+        let mut data = cdb();
+        data[MAIN_TABLE_SIZE_BYTES as usize + 8] = b'x';
+
+        match sole_problem(&data) {
+            CDBError::HashMismatch { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn errors_other_than_cdb_errors_are_kept() {
+        match problem(failure::err_msg("no such file")) {
+            CDBError::Unreadable { ref reason } if reason == "no such file" => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+        match problem(CDBError::MissingChecksum.into()) {
+            CDBError::MissingChecksum => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn entry_in_the_wrong_slot() {
+        let mut data = cdb_of(&[("abc", "def")]);
+        let pos = first_entry_pos(&data);
+        let entry = data[pos..(pos + 8)].to_vec();
+
+        // the record has its table to itself, so it sits in its home slot.
+        // move it one slot along, leaving a gap between it and home
+        let table = first_table(&data);
+        let other = table.start + (pos - table.start + 8) % table.len();
+
+        data[other..(other + 8)].copy_from_slice(&entry);
+        for x in &mut data[pos..(pos + 8)] {
+            *x = 0;
+        }
+
+        match sole_problem(&data) {
+            CDBError::MisplacedIndexEntry { .. } => (),
+            e => panic!("wrong error: {:?}", e),
+        }
+    }
+}
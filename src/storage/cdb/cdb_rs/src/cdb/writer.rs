@@ -0,0 +1,1700 @@
+use super::compress::{self, Compression, Encoder};
+use super::filter::{self, Filter};
+use super::layout::{Layout, Relocation, Spool};
+use super::spill::Spill;
+use super::stats::{self, BuildStats, Sizes};
+use super::trailer::{self, Trailer};
+use super::{Bucket, CDBError, HashFn, IndexEntry, MainTable, PointerWidth, Reader, Result};
+use bytes::BytesMut;
+use memmap::Mmap;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+
+fn ready_buf(size: usize) -> BytesMut {
+    let mut b = BytesMut::with_capacity(size);
+    b.reserve(size);
+    b
+}
+
+/// What `Writer::put` does with a key that's already been put.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateKeys {
+    /// Store every record, see `Reader::get_all`.
+    Allow,
+    /// Fail with `CDBError::DuplicateKey`, without writing the record.
+    Reject,
+    /// Silently skip the record.
+    KeepFirst,
+    /// Point the index at the new record instead of the old one. The old
+    /// record has already been written, so it stays in the data segment where
+    /// `Reader::iter` will still find it, but lookups never see it.
+    KeepLast,
+}
+
+impl Default for DuplicateKeys {
+    fn default() -> Self {
+        DuplicateKeys::Allow
+    }
+}
+
+// a secondary table laid out by build_table
+struct Table {
+    slots: usize,
+    buf: BytesMut,
+    entries: u64,
+    max_probe: u64,
+    total_probe: u64,
+}
+
+// values smaller than this are stored as they are unless asked otherwise.
+// against a dictionary even short values shrink, so there's no minimum then
+const DEFAULT_MIN_COMPRESSED_SIZE: usize = 64;
+
+// a dictionary is trained once this many bytes of values per byte of it have
+// been put, as zstd suggests
+const SAMPLES_PER_DICTIONARY_BYTE: usize = 100;
+
+// the puts held back to train a dictionary on
+struct Training {
+    size: usize,
+    puts: Vec<(Vec<u8>, Vec<u8>, u64)>,
+    bytes: usize,
+}
+
+impl Training {
+    // holds back a put, returning whether there's enough to train on
+    fn hold(&mut self, key: &[u8], value: &[u8], hint: u64) -> bool {
+        self.puts.push((key.to_vec(), value.to_vec(), hint));
+        self.bytes += value.len();
+        self.bytes >= self.size.saturating_mul(SAMPLES_PER_DICTIONARY_BYTE)
+    }
+}
+
+// the load factor secondary tables get unless asked otherwise, which is
+// what djb's writer uses
+const DEFAULT_LOAD_FACTOR: f64 = 0.5;
+
+// the number of slots for a secondary table of 'entries' entries. every table
+// starts off with an empty entry, so there's always a free slot for a lookup
+// of a missing key to stop at, even with a load factor of 1
+fn table_slots(entries: usize, load_factor: f64) -> usize {
+    (entries as f64 / load_factor).ceil() as usize
+}
+
+// how a secondary table is laid out
+#[derive(Copy, Clone)]
+struct TableShape {
+    width: PointerWidth,
+    main_table: MainTable,
+    load_factor: f64,
+}
+
+// lays out the secondary table holding 'tbl'
+fn build_table(tbl: Vec<IndexEntry>, shape: TableShape) -> Table {
+    let TableShape { width, main_table, load_factor } = shape;
+    let length = table_slots(tbl.len(), load_factor);
+    let mut ordered: Vec<IndexEntry> = vec![IndexEntry::default(); length];
+    let mut entries = 0;
+    let mut max_probe = 0;
+    let mut total_probe = 0;
+
+    for idx_ent in tbl {
+        // placing an empty entry would change nothing
+        if idx_ent.ptr == 0 {
+            continue;
+        }
+
+        let slot = main_table.slot(idx_ent.hash, length);
+        for i in 0..length {
+            let j = (i + slot) % length;
+            if ordered[j].ptr == 0 {
+                ordered[j] = idx_ent;
+                entries += 1;
+                max_probe = max_probe.max(i as u64);
+                total_probe += i as u64;
+                break;
+            }
+        }
+    }
+
+    let mut buf = ready_buf(length * width.pair_size());
+    for idx_ent in ordered {
+        width.put(&mut buf, u64::from(idx_ent.hash.inner()));
+        width.put(&mut buf, idx_ent.ptr);
+    }
+
+    Table { slots: length, buf, entries, max_probe, total_probe }
+}
+
+/// Options for building a cdb file, see `Writer::with_config`.
+#[derive(Clone, Debug, Default)]
+pub struct WriterConfig {
+    trailer: bool,
+    checksum: bool,
+    pointer_width: PointerWidth,
+    hash_fn: HashFn,
+    duplicates: DuplicateKeys,
+    max_index_memory: Option<usize>,
+    threads: usize,
+    layout: Layout,
+    load_factor: Option<f64>,
+    main_table: MainTable,
+    compression: Compression,
+    min_compressed_size: Option<usize>,
+    filter: Option<f64>,
+    count_duplicates: bool,
+}
+
+impl WriterConfig {
+    /// Appends a trailer describing the file even when it doesn't use any
+    /// features that need one. It's written regardless when it's needed.
+    pub fn trailer(&mut self, trailer: bool) -> &mut Self {
+        self.trailer = trailer;
+        self
+    }
+
+    /// Appends a trailer carrying a checksum of the whole file, which can be
+    /// checked on load with `Backend::load_path_verified` and friends. Readers
+    /// of the original format ignore the trailer.
+    pub fn checksum(&mut self, checksum: bool) -> &mut Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// `PointerWidth::U64` writes a cdb64 file, which can grow past 4 GiB but
+    /// can only be read by this crate. Defaults to `PointerWidth::U32`, where
+    /// growing the file past 4 GiB is an error.
+    pub fn pointer_width(&mut self, width: PointerWidth) -> &mut Self {
+        self.pointer_width = width;
+        self
+    }
+
+    /// The function keys are hashed with. Defaults to `HashFn::Djb`, any other
+    /// function means the file can only be read by this crate.
+    pub fn hash_fn(&mut self, hash_fn: HashFn) -> &mut Self {
+        self.hash_fn = hash_fn;
+        self
+    }
+
+    /// What to do when a key is put more than once. Defaults to
+    /// `DuplicateKeys::Allow`. Any other policy has the writer keep a copy of
+    /// every key in memory until it's finished.
+    pub fn duplicates(&mut self, duplicates: DuplicateKeys) -> &mut Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    /// Counts the keys put more than once under `DuplicateKeys::Allow`, for
+    /// `BuildStats::duplicates`. Like the other policies, that has the writer
+    /// keep a copy of every key in memory until it's finished. Off by
+    /// default, other policies always count them.
+    pub fn count_duplicates(&mut self, count: bool) -> &mut Self {
+        self.count_duplicates = count;
+        self
+    }
+
+    /// Caps the memory the index takes up while records are being put at
+    /// roughly `bytes`. Past that, the writer spills index entries to an
+    /// anonymous temp file (in `TMPDIR`), and `finish` reads them back one
+    /// secondary table at a time. `finish` needs memory for the largest table
+    /// on top of the limit.
+    ///
+    /// This doesn't cover the keys kept by any `duplicates` policy other than
+    /// `DuplicateKeys::Allow`, or by `count_duplicates`.
+    pub fn max_index_memory(&mut self, bytes: usize) -> &mut Self {
+        self.max_index_memory = Some(bytes);
+        self
+    }
+
+    /// Lays out the secondary tables on `threads` threads when finishing. The
+    /// file comes out byte for byte the same as when it's done on the calling
+    /// thread, which is the default.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    /// The order records are written out in, see `Layout`. Defaults to
+    /// `Layout::Input`. Any other layout holds records back in a spool file
+    /// (in `TMPDIR`) until `finish`, which then copies them into the file,
+    /// and keeps a few dozen bytes per record in memory until then.
+    ///
+    /// `Reader::iter` yields records in the order they're laid out in, while
+    /// `Reader::get_all` still yields them in the order they were put.
+    pub fn layout(&mut self, layout: Layout) -> &mut Self {
+        self.layout = layout;
+        self
+    }
+
+    /// How full the secondary tables are, between 0 (exclusive) and 1. Fuller
+    /// tables take less space, but lookups probe further, especially for
+    /// keys that aren't there. Defaults to 0.5, as djb's writer does, and
+    /// needs nothing special from readers.
+    pub fn load_factor(&mut self, load_factor: f64) -> &mut Self {
+        self.load_factor = Some(load_factor);
+        self
+    }
+
+    /// The size of the main table. Defaults to djb's 256 buckets, anything
+    /// bigger means the file can only be read by this crate.
+    pub fn main_table(&mut self, main_table: MainTable) -> &mut Self {
+        self.main_table = main_table;
+        self
+    }
+
+    /// Compresses each value on its own, so lookups only ever decompress the
+    /// value they found. `Reader::get` decompresses values transparently,
+    /// other lookups hand back values as they're stored, see `Reader::value`.
+    /// Defaults to `Compression::None`, anything else means the file can only
+    /// be read by this crate.
+    ///
+    /// `Compression::ZstdDictionary` holds puts back in memory until there are
+    /// enough values to train on, so errors they'd return, like
+    /// `CDBError::DuplicateKey`, come from the put that sets off training, or
+    /// from `finish`.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Values smaller than `bytes` are stored as they are when compressing,
+    /// as are ones compression doesn't shrink. Defaults to 64, or to 0 with a
+    /// dictionary, which shrinks values too short to compress on their own.
+    pub fn min_compressed_size(&mut self, bytes: usize) -> &mut Self {
+        self.min_compressed_size = Some(bytes);
+        self
+    }
+
+    /// Embeds a Bloom filter over every key, which lookups consult before the
+    /// index, so most keys that aren't in the file are turned away without
+    /// reading the main table or a secondary table. `false_positive_rate` is
+    /// the share of them that still get looked up, between 0 and 1
+    /// (exclusive). The filter takes about 1.44 * log2(1 / rate) bits a key,
+    /// almost 10 for a rate of 1%, and the writer keeps 8 bytes per key in
+    /// memory until it's finished. Off by default, and when it's on the file
+    /// can only be read by this crate.
+    pub fn filter(&mut self, false_positive_rate: f64) -> &mut Self {
+        self.filter = Some(false_positive_rate);
+        self
+    }
+
+    // the encoder for values, if they're to be tagged with a codec
+    fn encoder(&self) -> Option<Encoder> {
+        match self.compression {
+            Compression::None => None,
+            _ => Some(self.tagging_encoder()),
+        }
+    }
+
+    fn tagging_encoder(&self) -> Encoder {
+        let min_size = match self.compression {
+            Compression::ZstdDictionary { .. } => self.min_size(true),
+            _ => self.min_size(false),
+        };
+        Encoder::new(self.compression, min_size)
+    }
+
+    fn min_size(&self, dictionary: bool) -> usize {
+        match self.min_compressed_size {
+            Some(min_size) => min_size,
+            None if dictionary => 0,
+            None => DEFAULT_MIN_COMPRESSED_SIZE,
+        }
+    }
+
+    // an encoder for a file that already has 'dictionary', at the level asked
+    // for if there is one
+    fn dictionary_encoder(&self, dictionary: &[u8]) -> Encoder {
+        let level = match self.compression {
+            Compression::Zstd { level } | Compression::ZstdDictionary { level, .. } => level,
+            _ => 0,
+        };
+        let compression = Compression::ZstdDictionary { level, size: dictionary.len() };
+
+        let mut encoder = Encoder::new(compression, self.min_size(true));
+        encoder.set_dictionary(dictionary);
+        encoder
+    }
+
+    fn training(&self) -> Option<Training> {
+        match self.compression {
+            Compression::ZstdDictionary { size, .. } => Some(Training {
+                size,
+                puts: Vec::new(),
+                bytes: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.load_factor {
+            Some(load_factor) if !(load_factor > 0.0 && load_factor <= 1.0) => {
+                return Err(CDBError::InvalidLoadFactor { load_factor }.into());
+            }
+            _ => (),
+        }
+        match self.filter {
+            Some(rate) if !(rate > 0.0 && rate < 1.0) => {
+                Err(CDBError::InvalidFalsePositiveRate { rate }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What `Writer::finish` wrote.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BuildSummary {
+    /// The number of records lookups can reach, counting every duplicate
+    /// that was allowed.
+    pub records: usize,
+    /// The number of records dropped by `DuplicateKeys::KeepFirst` or
+    /// `DuplicateKeys::KeepLast`.
+    pub dropped: usize,
+    /// The size of the finished file in bytes.
+    pub len: u64,
+    /// The region holding the records.
+    pub data_segment: Range<u64>,
+    /// The region holding the secondary tables.
+    pub index: Range<u64>,
+    /// The region holding the zstd dictionary, if one was trained, see
+    /// `Compression::ZstdDictionary`.
+    pub dictionary: Option<Range<u64>>,
+    /// The region holding the filter over the keys, if one was asked for,
+    /// see `WriterConfig::filter`.
+    pub filter: Option<Range<u64>>,
+    /// The checksum stored in the trailer, if one was asked for.
+    pub checksum: Option<u64>,
+    pub stats: BuildStats,
+}
+
+impl BuildSummary {
+    /// The summary as a single line of JSON, for build pipelines to keep
+    /// track of.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.into())
+    }
+}
+
+/// Builds a cdb file. Records are written out as they're `put`, the index is
+/// only written by `finish`.
+///
+/// Until then the main table is filled with 0xff, which points every bucket
+/// far past the end of the file. A writer that's dropped without being
+/// finished, or whose `finish` fails, leaves behind a file that fails every
+/// lookup and `verify`, rather than one that looks empty or half-full.
+pub struct Writer<F>
+    where
+        F: Write + Seek,
+{
+    file: F,
+    // the index entries that haven't been spilled, by table
+    index: Vec<Vec<IndexEntry>>,
+    pending: usize,
+    max_index_memory: Option<usize>,
+    spill: Option<Spill>,
+    threads: usize,
+    records: usize,
+    dropped: usize,
+    checksum: Option<trailer::Checksum>,
+    width: PointerWidth,
+    hash_fn: HashFn,
+    trailer: bool,
+    duplicates: DuplicateKeys,
+    // every key put so far, along with where its entry is in its table. only
+    // kept when duplicates aren't allowed
+    seen: HashMap<Vec<u8>, usize>,
+    // every key put so far when duplicates are allowed but counted
+    counted: Option<HashSet<Vec<u8>>>,
+    // entries that `DuplicateKeys::KeepLast` repointed after they'd been
+    // spilled, by table
+    repointed: Vec<Vec<(usize, u64)>>,
+    duplicate_puts: usize,
+    key_sizes: Sizes,
+    value_sizes: Sizes,
+    layout: Layout,
+    // the records held back for 'layout'
+    spool: Option<Spool>,
+    // where the spooled records ended up, once they've been written out
+    relocation: Option<Relocation>,
+    load_factor: f64,
+    main_table: MainTable,
+    // set when every value is tagged with a codec
+    encoder: Option<Encoder>,
+    compressed: usize,
+    // set until there's a dictionary to compress against
+    training: Option<Training>,
+    dictionary: Option<Vec<u8>>,
+    filter: Option<filter::Builder>,
+}
+
+impl<F> Writer<F>
+    where
+        F: Write + Seek,
+{
+    pub fn new(file: F) -> Result<Writer<F>> {
+        Writer::with_config(file, &WriterConfig::default())
+    }
+
+    pub fn with_config(mut file: F, config: &WriterConfig) -> Result<Writer<F>> {
+        config.check()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&vec![0xffu8; config.main_table.size(config.pointer_width)])?;
+        Ok(Writer::setup(file, config))
+    }
+
+    fn setup(file: F, config: &WriterConfig) -> Writer<F> {
+        let width = config.pointer_width;
+        let buckets = config.main_table.buckets();
+        Writer {
+            file,
+            index: vec![vec![IndexEntry::default()]; buckets],
+            pending: buckets,
+            max_index_memory: config.max_index_memory,
+            spill: None,
+            threads: config.threads,
+            records: 0,
+            dropped: 0,
+            checksum: if config.checksum { Some(trailer::Checksum::new()) } else { None },
+            width,
+            hash_fn: config.hash_fn,
+            trailer: config.trailer,
+            duplicates: config.duplicates,
+            seen: HashMap::new(),
+            counted: match config.duplicates {
+                DuplicateKeys::Allow if config.count_duplicates => Some(HashSet::new()),
+                _ => None,
+            },
+            repointed: vec![Vec::new(); buckets],
+            duplicate_puts: 0,
+            key_sizes: Sizes::default(),
+            value_sizes: Sizes::default(),
+            layout: config.layout,
+            spool: None,
+            relocation: None,
+            load_factor: config.load_factor.unwrap_or(DEFAULT_LOAD_FACTOR),
+            main_table: config.main_table,
+            encoder: config.encoder(),
+            compressed: 0,
+            training: config.training(),
+            dictionary: None,
+            filter: config.filter.map(filter::Builder::new),
+        }
+    }
+
+    fn seek(&mut self, sf: SeekFrom) -> Result<u64> {
+        self.file.seek(sf).map_err(|e| e.into())
+    }
+
+    // makes sure that writing up to 'end' won't take the file past what its
+    // pointers can address
+    fn check_size(&self, end: u64) -> Result<()> {
+        let max_size = self.width.max_offset();
+        if end > max_size {
+            return Err(CDBError::FileTooLarge { max_size, size: end }.into());
+        }
+        Ok(())
+    }
+
+    // writes out `buf` at the current position, keeping the checksum up to date
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(ref mut ck) = self.checksum {
+            ck.update(buf);
+        }
+        self.file.write_all(buf).map_err(|e| e.into())
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_with_hint(key, value, 0)
+    }
+
+    /// Puts a record along with a hint of how often it's read, which places
+    /// it under `Layout::Hint`. Other layouts ignore the hint.
+    pub fn put_with_hint(&mut self, key: &[u8], value: &[u8], hint: u64) -> Result<()> {
+        match self.training.as_mut().map(|t| t.hold(key, value, hint)) {
+            Some(true) => return self.train(),
+            Some(false) => return Ok(()),
+            None => (),
+        }
+
+        let dup = self.seen.get(key).cloned();
+        if dup.is_some() {
+            self.duplicate_puts += 1;
+            match self.duplicates {
+                DuplicateKeys::Reject => return Err(CDBError::duplicate_key(key).into()),
+                DuplicateKeys::KeepFirst => {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+
+        if self.layout != Layout::Input && self.spool.is_none() {
+            let base = self.seek(SeekFrom::Current(0))?;
+            self.spool = Some(Spool::new(base)?);
+        }
+
+        let ptr = match self.spool {
+            Some(ref spool) => spool.position(),
+            None => self.seek(SeekFrom::Current(0))?,
+        };
+        let mut encoded = Vec::new();
+        let stored = match self.encoder {
+            Some(ref mut encoder) => {
+                if encoder.encode(value, &mut encoded)? {
+                    self.compressed += 1;
+                }
+                &encoded[..]
+            }
+            None => value,
+        };
+
+        let width = self.width;
+        let len = width.pair_size() + key.len() + stored.len();
+        self.check_size(ptr + len as u64)?;
+
+        let mut buf = ready_buf(len);
+
+        width.put(&mut buf, key.len() as u64);
+        width.put(&mut buf, stored.len() as u64);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(stored);
+
+        let hash = self.hash_fn.hash(key);
+        match self.spool {
+            Some(ref mut spool) => spool.push(&buf[..], hash, hint)?,
+            None => self.write_all(&buf[..])?,
+        }
+        self.key_sizes.add(key.len());
+        self.value_sizes.add(value.len());
+        if let Some(ref mut filter) = self.filter {
+            filter.add(key);
+        }
+
+        let t = self.main_table.table(hash);
+        let spilled = self.spill.as_ref().map(|s| s.spilled(t)).unwrap_or(0);
+        match dup {
+            Some(n) if n < spilled => {
+                self.repointed[t].push((n, ptr));
+                self.dropped += 1;
+            }
+            Some(n) => {
+                self.index[t][n - spilled].ptr = ptr;
+                self.dropped += 1;
+            }
+            None => {
+                let n = self.push_entry(IndexEntry { hash, ptr })?;
+                if self.duplicates != DuplicateKeys::Allow {
+                    self.seen.insert(key.to_vec(), n);
+                } else if let Some(ref mut counted) = self.counted {
+                    if !counted.insert(key.to_vec()) {
+                        self.duplicate_puts += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // trains the dictionary on the values held back, then puts them for real.
+    // without enough of them to train on, values are compressed on their own
+    fn train(&mut self) -> Result<()> {
+        let training = match self.training.take() {
+            Some(training) => training,
+            None => return Ok(()),
+        };
+
+        let dictionary = {
+            let values: Vec<&[u8]> = training.puts.iter().map(|p| &p.1[..]).collect();
+            compress::train(&values, training.size)
+        };
+        if let Some(dictionary) = dictionary {
+            if let Some(ref mut encoder) = self.encoder {
+                encoder.set_dictionary(&dictionary);
+            }
+            self.dictionary = Some(dictionary);
+        }
+
+        // a put that fails, like a rejected duplicate, doesn't hold up the rest
+        let mut res = Ok(());
+        for (key, value, hint) in training.puts {
+            let put = self.put_with_hint(&key, &value, hint);
+            if res.is_ok() {
+                res = put;
+            }
+        }
+        res
+    }
+
+    // adds a record to the index, returning where its entry is in its table
+    fn push_entry(&mut self, entry: IndexEntry) -> Result<usize> {
+        let t = self.main_table.table(entry.hash);
+        let n = self.table_len(t);
+        self.index[t].push(entry);
+        self.records += 1;
+        self.pending += 1;
+
+        let in_memory = self.pending * mem::size_of::<IndexEntry>();
+        match self.max_index_memory {
+            Some(limit) if in_memory > limit => self.spill_index()?,
+            _ => (),
+        }
+        Ok(n)
+    }
+
+    // moves every pending index entry out to the spill file
+    fn spill_index(&mut self) -> Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(Spill::new(self.main_table.buckets())?);
+        }
+        if let Some(ref mut spill) = self.spill {
+            spill.push_run(&mut self.index)?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+
+    // the number of entries in table 't', spilled or not
+    fn table_len(&self, t: usize) -> usize {
+        self.spill.as_ref().map(|s| s.spilled(t)).unwrap_or(0) + self.index[t].len()
+    }
+
+    // takes every entry of table 't', in the order they were put
+    fn take_table(&mut self, t: usize) -> Result<Vec<IndexEntry>> {
+        let pending = mem::replace(&mut self.index[t], Vec::new());
+        let mut tbl = match self.spill {
+            Some(ref mut spill) => {
+                let mut tbl = spill.table(t)?;
+                tbl.extend(pending);
+                for &(n, ptr) in &self.repointed[t] {
+                    tbl[n].ptr = ptr;
+                }
+                tbl
+            }
+            None => pending,
+        };
+
+        if let Some(ref relocation) = self.relocation {
+            for ie in &mut tbl {
+                ie.ptr = relocation.ptr(ie.ptr);
+            }
+        }
+        Ok(tbl)
+    }
+
+    /// Writes out the index, after which the file is complete. The file is
+    /// flushed, but not synced.
+    pub fn finish(mut self) -> Result<BuildSummary> {
+        self.train()?;
+
+        let width = self.width;
+        let main_table_size = self.main_table.size(width);
+        let tables = self.main_table.buckets();
+        let mut buckets: Vec<Bucket> = Vec::with_capacity(tables);
+
+        if let Some(spool) = self.spool.take() {
+            let slots: Vec<usize> = (0..tables)
+                .map(|t| table_slots(self.table_len(t), self.load_factor))
+                .collect();
+            let (layout, main_table) = (self.layout, self.main_table);
+            let relocation =
+                spool.write_out(layout, main_table, &slots, |rec| self.write_all(rec))?;
+            self.relocation = Some(relocation);
+        }
+
+        let data_end = self.seek(SeekFrom::End(0))?;
+
+        // the tables are laid out a batch at a time, one per thread, so only
+        // a batch's worth is ever in memory
+        let pool = match self.threads {
+            n if n > 1 => Some(ThreadPoolBuilder::new().num_threads(n).build()?),
+            _ => None,
+        };
+        let batch = self.threads.max(1);
+
+        let mut table_entries = Vec::with_capacity(tables);
+        let mut max_probe = 0;
+        let mut total_probe = 0;
+
+        let shape = TableShape {
+            width,
+            main_table: self.main_table,
+            load_factor: self.load_factor,
+        };
+
+        for first in (0..tables).step_by(batch) {
+            let last = (first + batch).min(tables);
+            let mut tbls = Vec::with_capacity(last - first);
+            for t in first..last {
+                tbls.push(self.take_table(t)?);
+            }
+
+            let build = |tbl| build_table(tbl, shape);
+            let built: Vec<Table> = match pool {
+                Some(ref pool) => pool.install(|| tbls.into_par_iter().map(build).collect()),
+                None => tbls.into_iter().map(build).collect(),
+            };
+
+            // move to EOF and write out the secondary index entries, constructing the
+            // primary table as we go ('buckets')
+            //
+            for table in built {
+                let ptr = self.seek(SeekFrom::End(0))?;
+                self.check_size(ptr + table.buf.len() as u64)?;
+
+                buckets.push(Bucket {
+                    ptr,
+                    num_ents: table.slots as u64,
+                });
+
+                self.write_all(&table.buf[..])?;
+
+                table_entries.push(table.entries);
+                max_probe = max_probe.max(table.max_probe);
+                total_probe += table.total_probe;
+            }
+        }
+
+        let index_end = self.seek(SeekFrom::Current(0))?;
+
+        let dictionary = match self.dictionary.take() {
+            Some(dictionary) => {
+                let end = index_end + dictionary.len() as u64;
+                self.check_size(end)?;
+                self.write_all(&dictionary)?;
+                Some(index_end..end)
+            }
+            None => None,
+        };
+
+        let filter = match self.filter.take() {
+            Some(filter) => {
+                let filter = filter.build();
+                let start = self.seek(SeekFrom::Current(0))?;
+                let end = start + filter.len() as u64;
+                self.check_size(end)?;
+                self.write_all(&filter)?;
+                Some(start..end)
+            }
+            None => None,
+        };
+
+        let mut main_table = ready_buf(main_table_size);
+        for bkt in buckets {
+            width.put(&mut main_table, bkt.ptr);
+            width.put(&mut main_table, bkt.num_ents);
+        }
+
+        // the trailer goes after the tables, and its checksum takes in the
+        // main table last, so it's done before the main table is written.
+        // files that stray from djb's format always get one, it's how readers
+        // recognise them
+        //
+        let ck = self.checksum.take();
+        let mut checksum = None;
+        let plain = width == PointerWidth::U32
+            && self.hash_fn == HashFn::Djb
+            && self.main_table == MainTable::default()
+            && self.encoder.is_none()
+            && filter.is_none();
+        if self.trailer || ck.is_some() || !plain {
+            let mut t = Trailer {
+                pointer_width: width,
+                hash_fn: self.hash_fn,
+                main_table: self.main_table,
+                ..Trailer::default()
+            };
+            if self.main_table != MainTable::default() {
+                t.flags |= trailer::FLAG_MAIN_TABLE;
+            }
+            if self.encoder.is_some() {
+                t.flags |= trailer::FLAG_COMPRESSED;
+            }
+            if let Some(ref dictionary) = dictionary {
+                t.flags |= trailer::FLAG_DICTIONARY;
+                t.dictionary_offset = dictionary.start;
+                t.dictionary_len = (dictionary.end - dictionary.start) as u32;
+            }
+            if let Some(ref filter) = filter {
+                t.flags |= trailer::FLAG_FILTER;
+                t.filter_offset = filter.start;
+                t.filter_len = filter.end - filter.start;
+            }
+            if let Some(mut ck) = ck {
+                t.flags |= trailer::FLAG_CHECKSUM;
+                ck.update_trailer(&t.encode()[..]);
+                ck.update(&main_table[..]);
+                t.checksum = ck.finish();
+                checksum = Some(t.checksum);
+            }
+            self.file.write_all(&t.encode()[..])?;
+        }
+
+        let len = self.seek(SeekFrom::Current(0))?;
+
+        // now write the buckets, which makes the file valid
+        //
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&main_table[..])?;
+        self.file.flush()?;
+
+        // start at BOF
+        self.file.seek(SeekFrom::Start(0))?;
+
+        Ok(BuildSummary {
+            records: self.records,
+            dropped: self.dropped,
+            len,
+            data_segment: (main_table_size as u64)..data_end,
+            index: data_end..index_end,
+            dictionary,
+            filter,
+            checksum,
+            stats: BuildStats {
+                data_bytes: data_end - main_table_size as u64,
+                index_bytes: index_end - data_end,
+                key_sizes: self.key_sizes.stats(),
+                value_sizes: self.value_sizes.stats(),
+                max_probe,
+                mean_probe: stats::mean(total_probe, table_entries.iter().sum()),
+                table_entries,
+                duplicates: match self.duplicates {
+                    DuplicateKeys::Allow if self.counted.is_none() => None,
+                    _ => Some(self.duplicate_puts),
+                },
+                compressed: self.compressed,
+            },
+        })
+    }
+}
+
+impl Writer<BufWriter<File>> {
+    /// Opens the finished cdb file `file` to put more records in, without
+    /// rewriting the ones already there. `file` has to be open for reading and
+    /// writing, and mustn't be changed by anyone else until the writer's done.
+    ///
+    /// The index is rebuilt from the file's secondary tables and the old
+    /// tables are truncated away, so the file is invalid until `finish`
+    /// writes the new ones. Copy it first if it's being read from.
+    ///
+    /// The file keeps its pointer width, hash function and main table,
+    /// whatever `config` says, and keeps its trailer and checksum if it has
+    /// them. New values are only compressed if the file was written with
+    /// `WriterConfig::compression`, and then the way `config` asks, against
+    /// the file's dictionary if it has one. A file with a filter keeps one,
+    /// at the rate `config` asks for, or one close to the file's own if it
+    /// doesn't ask. A file with a checksum is verified before anything is
+    /// changed. Records that were dropped by `DuplicateKeys::KeepLast` stay
+    /// dropped. An empty file is started from scratch.
+    pub fn append(file: File, config: &WriterConfig) -> Result<Writer<BufWriter<File>>> {
+        config.check()?;
+        if file.metadata()?.len() == 0 {
+            return Writer::with_config(BufWriter::new(file), config);
+        }
+
+        let (mut w, data_end) = {
+            let map = unsafe { Mmap::map(&file)? };
+            let reader = Reader::new(&map[..])?;
+
+            let mut config = config.clone();
+            config
+                .pointer_width(reader.pointer_width())
+                .hash_fn(reader.hash_fn())
+                .main_table(reader.main_table());
+            if let Some(t) = reader.trailer() {
+                config.trailer(true);
+                if t.has_checksum() {
+                    trailer::verify_checksum(&reader)?;
+                    config.checksum(true);
+                }
+            }
+
+            let mut w = Writer::setup(BufWriter::new(file.try_clone()?), &config);
+            // the file's values either all carry a codec tag or none do, and a
+            // file keeps the dictionary it has
+            let compressed = reader.trailer().map(|t| t.is_compressed()).unwrap_or(false);
+            match reader.dictionary() {
+                _ if !compressed => {
+                    w.encoder = None;
+                    w.training = None;
+                }
+                Some(dictionary) => {
+                    w.encoder = Some(config.dictionary_encoder(dictionary));
+                    w.training = None;
+                    w.dictionary = Some(dictionary.to_vec());
+                }
+                None => w.encoder = Some(config.tagging_encoder()),
+            }
+            if w.filter.is_none() {
+                let filter = reader.filter().and_then(Filter::new);
+                w.filter = filter.map(|f| filter::Builder::new(f.false_positive_rate()));
+            }
+            let data_end = w.load(&reader)?;
+            (w, data_end)
+        };
+
+        let main_table = w.main_table.size(w.width);
+        {
+            // nothing's been buffered yet, so the file can be used directly
+            let f = w.file.get_mut();
+            f.set_len(data_end as u64)?;
+            f.seek(SeekFrom::Start(0))?;
+            f.write_all(&vec![0xffu8; main_table])?;
+            f.seek(SeekFrom::Start(data_end as u64))?;
+        }
+
+        Ok(w)
+    }
+
+    // takes in the records 'reader' can look up, as though they'd just been
+    // put, returning where the data segment ends
+    fn load(&mut self, reader: &Reader) -> Result<usize> {
+        let main_table = self.main_table.size(self.width);
+        let data_end = reader.bucket_at(0)?.ptr as usize;
+        if data_end < main_table || data_end > reader.len() {
+            return Err(CDBError::IndexOutOfDataSegment {
+                valid_range: main_table..reader.len(),
+                ptr_val: data_end,
+            }.into());
+        }
+
+        for t in 0..self.main_table.buckets() {
+            let bucket = reader.bucket_at(t)?;
+            let num_ents = bucket.num_ents as usize;
+            let mut entries = Vec::new();
+            for n in 0..bucket.num_ents {
+                let ie = reader.index_entry_at(bucket.entry_n_pos(n, self.width))?;
+                if ie.ptr == 0 {
+                    continue;
+                }
+                if ie.ptr < main_table as u64 || ie.ptr >= data_end as u64 {
+                    return Err(CDBError::IndexOutOfDataSegment {
+                        valid_range: main_table..data_end,
+                        ptr_val: ie.ptr as usize,
+                    }.into());
+                }
+                // how far along its probe sequence the entry sits
+                let home = self.main_table.slot(ie.hash, num_ents);
+                entries.push(((n as usize + num_ents - home) % num_ents, ie));
+            }
+
+            // records are written in the order they were put under the default
+            // layout, so rebuilding the table in that order places each entry
+            // where a single build would have
+            entries.sort_by_key(|&(_, ie)| ie.ptr);
+
+            // other layouts write records in an order of their own, but
+            // records with the same hash sit along their probe sequence in the
+            // order they were put, so that's the order they're taken in
+            let mut same_hash: HashMap<u32, Vec<usize>> = HashMap::new();
+            for (i, &(_, ie)) in entries.iter().enumerate() {
+                same_hash.entry(ie.hash.inner()).or_default().push(i);
+            }
+            for places in same_hash.values().filter(|places| places.len() > 1) {
+                let mut group: Vec<_> = places.iter().map(|&i| entries[i]).collect();
+                group.sort_by_key(|&(probe, _)| probe);
+                for (&i, entry) in places.iter().zip(group) {
+                    entries[i] = entry;
+                }
+            }
+
+            for (_, ie) in entries {
+                let kv = reader.kv_ref_at(ie.ptr as usize)?;
+                // the file's cut back to the end of the data segment, so a
+                // record running past it would be left dangling
+                let ptr = ie.ptr as usize;
+                let end = ptr + self.width.pair_size() + kv.k.len() + kv.v.len();
+                if end > data_end {
+                    return Err(CDBError::RecordOverrun {
+                        valid_range: main_table..data_end,
+                        record: ptr..end,
+                    }.into());
+                }
+                self.key_sizes.add(kv.k.len());
+                if let Some(ref mut filter) = self.filter {
+                    filter.add(kv.k);
+                }
+                if self.encoder.is_some() {
+                    self.value_sizes.add(compress::decoded_len(kv.v)?);
+                    if compress::is_compressed(kv.v) {
+                        self.compressed += 1;
+                    }
+                } else {
+                    self.value_sizes.add(kv.v.len());
+                }
+
+                let n = self.push_entry(ie)?;
+                let dup = if self.duplicates != DuplicateKeys::Allow {
+                    self.seen.insert(kv.k.to_vec(), n).is_some()
+                } else if let Some(ref mut counted) = self.counted {
+                    !counted.insert(kv.k.to_vec())
+                } else {
+                    false
+                };
+                if dup {
+                    self.duplicate_puts += 1;
+                }
+            }
+        }
+
+        if let Some(ref mut ck) = self.checksum {
+            ck.update(&reader[main_table..data_end]);
+        }
+
+        Ok(data_end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdb::{cdb_handle, verify, CDBHash};
+    use cdb::stats::SizeStats;
+    use std::io::{self, Cursor, Read};
+    use tempfile;
+
+    #[test]
+    fn finish_summarises_the_file() {
+        let mut cur = Cursor::new(Vec::new());
+        let summary = {
+            let mut w = Writer::with_config(&mut cur, WriterConfig::default().checksum(true)).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            w.put(b"abc", b"ghi").unwrap();
+            w.finish().unwrap()
+        };
+        let data = cur.into_inner();
+
+        assert_eq!(summary.records, 2);
+        assert_eq!(summary.len, data.len() as u64);
+        assert_eq!(summary.data_segment, 2048..(2048 + 2 * 14));
+        assert_eq!(summary.index.start, summary.data_segment.end);
+
+        let (start, t) = Trailer::find(&data).unwrap().unwrap();
+        assert_eq!(summary.index.end, start as u64);
+        assert_eq!(summary.checksum, Some(t.checksum));
+
+        let stats = &summary.stats;
+        assert_eq!(stats.data_bytes, 2 * 14);
+        assert_eq!(stats.index_bytes, summary.index.end - summary.index.start);
+        assert_eq!(stats.key_sizes, SizeStats { min: 3, max: 3, mean: 3.0 });
+        assert_eq!(stats.table_entries.len(), 256);
+        assert_eq!(stats.table_entries.iter().sum::<u64>(), 2);
+        assert_eq!(stats.duplicates, None);
+
+        let json = summary.to_json().unwrap();
+        assert!(json.starts_with(r#"{"records":2,"dropped":0,"len":"#), "{}", json);
+        assert!(json.contains(r#""key_sizes":{"min":3,"max":3,"mean":3.0}"#), "{}", json);
+    }
+
+    #[test]
+    fn allowed_duplicates_are_counted_on_request() {
+        let mut cur = Cursor::new(Vec::new());
+        let summary = {
+            let config = WriterConfig::default().count_duplicates(true).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            for n in 0..2000 {
+                w.put(format!("key{}", n % 1500).as_bytes(), b"v").unwrap();
+            }
+            w.finish().unwrap()
+        };
+        assert_eq!((summary.records, summary.dropped), (2000, 0));
+        assert_eq!(summary.stats.duplicates, Some(500));
+    }
+
+    #[test]
+    fn stats_describe_the_data() {
+        let mut cur = Cursor::new(Vec::new());
+        let summary = {
+            let config = WriterConfig::default().duplicates(DuplicateKeys::KeepLast).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            for n in 0..2000 {
+                let k = format!("key{}", n % 1500);
+                w.put(k.as_bytes(), &vec![b'v'; n % 10]).unwrap();
+            }
+            w.finish().unwrap()
+        };
+        let stats = summary.stats;
+
+        assert_eq!(stats.duplicates, Some(500));
+        assert_eq!(stats.value_sizes, SizeStats { min: 0, max: 9, mean: 4.5 });
+        assert_eq!((stats.key_sizes.min, stats.key_sizes.max), (4, 7));
+        assert_eq!(stats.table_entries.iter().sum::<u64>(), 1500);
+
+        // work the probe distances out from the file itself
+        let data = cur.into_inner();
+        let cdb = Reader::new(&data).unwrap();
+        let mut probes = Vec::new();
+        for t in 0..256 {
+            let b = cdb.bucket_at(t).unwrap();
+            for n in 0..b.num_ents {
+                let ie = cdb.index_entry_at(b.entry_n_pos(n, PointerWidth::U32)).unwrap();
+                if ie.ptr != 0 {
+                    let home = MainTable::default().slot(ie.hash, b.num_ents as usize) as u64;
+                    probes.push((n + b.num_ents - home) % b.num_ents);
+                }
+            }
+        }
+        assert_eq!(stats.max_probe, *probes.iter().max().unwrap());
+        let mean = probes.iter().sum::<u64>() as f64 / probes.len() as f64;
+        assert!((stats.mean_probe - mean).abs() < 1e-9);
+    }
+
+    fn write_with_duplicates(duplicates: DuplicateKeys) -> (Result<BuildSummary>, Vec<u8>) {
+        let mut cur = Cursor::new(Vec::new());
+        let res = {
+            let config = WriterConfig::default().duplicates(duplicates).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            w.put(b"abc", b"one")
+                .and_then(|_| w.put(b"xyz", b"zzz"))
+                .and_then(|_| w.put(b"abc", b"two"))
+                .and_then(|_| w.put(b"abc", b"three"))
+                .and_then(|_| w.finish())
+        };
+        (res, cur.into_inner())
+    }
+
+    fn values(data: &[u8], key: &[u8]) -> Vec<Vec<u8>> {
+        let cdb = Reader::new(data).unwrap();
+        cdb.get_all(key).unwrap().map(|kv| kv.unwrap().v.to_vec()).collect()
+    }
+
+    #[test]
+    fn duplicate_key_policies() {
+        let (summary, data) = write_with_duplicates(DuplicateKeys::Allow);
+        assert_eq!(summary.unwrap().records, 4);
+        assert_eq!(
+            values(&data, b"abc"),
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+
+        let (summary, data) = write_with_duplicates(DuplicateKeys::KeepFirst);
+        let summary = summary.unwrap();
+        assert_eq!((summary.records, summary.dropped), (2, 2));
+        assert_eq!(values(&data, b"abc"), vec![b"one".to_vec()]);
+        assert_eq!(values(&data, b"xyz"), vec![b"zzz".to_vec()]);
+        assert!(verify::verify(&Reader::new(&data).unwrap()).is_ok());
+
+        let (summary, data) = write_with_duplicates(DuplicateKeys::KeepLast);
+        let summary = summary.unwrap();
+        assert_eq!((summary.records, summary.dropped), (2, 2));
+        assert_eq!(values(&data, b"abc"), vec![b"three".to_vec()]);
+        assert_eq!(values(&data, b"xyz"), vec![b"zzz".to_vec()]);
+        assert!(verify::verify(&Reader::new(&data).unwrap()).is_ok());
+
+        let (err, _) = write_with_duplicates(DuplicateKeys::Reject);
+        let err = err.unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::DuplicateKey { key }) => assert_eq!(key, "abc"),
+            _ => panic!("expected DuplicateKey, got: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn rejected_duplicates_are_not_written() {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let config = WriterConfig::default().duplicates(DuplicateKeys::Reject).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            assert!(w.put(b"abc", b"ghi").is_err());
+            w.put(b"\x00\xff", b"jkl").unwrap();
+
+            let err = w.put(b"\x00\xff", b"mno").unwrap_err();
+            assert_eq!(err.to_string(), "duplicate key \"\\x00\\xff\"");
+            assert_eq!(w.finish().unwrap().records, 2);
+        }
+        let data = cur.into_inner();
+
+        let cdb = Reader::new(&data).unwrap();
+        let records: Vec<_> = cdb.iter().unwrap().map(|kv| kv.unwrap().v.to_vec()).collect();
+        assert_eq!(records, vec![b"def".to_vec(), b"jkl".to_vec()]);
+    }
+
+    fn write_many(config: &WriterConfig) -> Vec<u8> {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::with_config(&mut cur, config).unwrap();
+            for round in 0..3 {
+                for n in 0..1000 {
+                    let v = format!("{}/{}", n, round);
+                    w.put(format!("key{}", n).as_bytes(), v.as_bytes()).unwrap();
+                }
+            }
+            w.finish().unwrap();
+        }
+        cur.into_inner()
+    }
+
+    #[test]
+    fn spilling_the_index_makes_the_same_file() {
+        let policies = [DuplicateKeys::Allow, DuplicateKeys::KeepFirst, DuplicateKeys::KeepLast];
+        for &duplicates in &policies {
+            let mut config = WriterConfig::default();
+            config.duplicates(duplicates);
+            let expected = write_many(&config);
+
+            config.max_index_memory(1024);
+            let data = write_many(&config);
+            assert!(data == expected, "{:?}", duplicates);
+        }
+
+        let config = WriterConfig::default()
+            .max_index_memory(1024)
+            .threads(3)
+            .duplicates(DuplicateKeys::KeepLast)
+            .clone();
+        let data = write_many(&config);
+        assert_eq!(values(&data, b"key123"), vec![b"123/2".to_vec()]);
+    }
+
+    #[test]
+    fn threaded_finish_makes_the_same_file() {
+        let expected = write_many(&WriterConfig::default());
+        for &threads in &[1, 2, 7, 300] {
+            let data = write_many(WriterConfig::default().threads(threads));
+            assert!(data == expected, "threads: {}", threads);
+        }
+    }
+
+    fn append_to(f: &mut File, config: &WriterConfig, kvs: &[(&str, &str)]) -> BuildSummary {
+        let mut w = Writer::append(f.try_clone().unwrap(), config).unwrap();
+        for (k, v) in kvs {
+            w.put(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    fn contents(f: &mut File) -> Vec<u8> {
+        let mut data = Vec::new();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn appending_makes_the_same_file_as_writing_it_all_at_once() {
+        // enough records that they crowd each other out of their home slots
+        let records: Vec<(String, String)> =
+            (0..6000).map(|n| (format!("key{}", n % 4500), format!("v{}", n))).collect();
+        let records: Vec<(&str, &str)> =
+            records.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        let mut first = vec![("abc", "def"), ("pink", "red"), ("abc", "ghi")];
+        first.extend_from_slice(&records[..3000]);
+        let mut second = vec![("apple", "grape"), ("abc", "jkl"), ("q", "burp")];
+        second.extend_from_slice(&records[3000..]);
+
+        let policies = [DuplicateKeys::Allow, DuplicateKeys::KeepFirst, DuplicateKeys::KeepLast];
+        for &duplicates in &policies {
+            for &width in &[PointerWidth::U32, PointerWidth::U64] {
+                let config = WriterConfig::default()
+                    .checksum(true)
+                    .pointer_width(width)
+                    .duplicates(duplicates)
+                    .clone();
+
+                let mut expected = tempfile::tempfile().unwrap();
+                let all: Vec<_> = first.iter().chain(second.iter()).cloned().collect();
+                append_to(&mut expected, &config, &all);
+
+                let mut f = tempfile::tempfile().unwrap();
+                append_to(&mut f, &config, &first);
+                // the file's own settings win over the config's
+                let config = WriterConfig::default()
+                    .duplicates(duplicates)
+                    .count_duplicates(true)
+                    .clone();
+                let summary = append_to(&mut f, &config, &second);
+
+                let data = contents(&mut f);
+                assert!(data == contents(&mut expected), "{:?} {:?}", duplicates, width);
+                assert_eq!(summary.len, data.len() as u64);
+                assert!(verify::verify(&Reader::new(&data).unwrap()).is_ok());
+                if duplicates == DuplicateKeys::Allow {
+                    assert_eq!(summary.stats.duplicates, Some(1502));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn appending_rejects_corrupt_files() {
+        let mut f = tempfile::tempfile().unwrap();
+        append_to(&mut f, WriterConfig::default().checksum(true), &[("abc", "def")]);
+
+        f.seek(SeekFrom::Start(2048 + 8)).unwrap();
+        f.write_all(b"x").unwrap();
+        let before = contents(&mut f);
+
+        let err = Writer::append(f.try_clone().unwrap(), &WriterConfig::default()).err().unwrap();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::ChecksumMismatch { .. }) => (),
+            _ => panic!("expected ChecksumMismatch, got: {:?}", err),
+        }
+        assert!(contents(&mut f) == before);
+
+        // a record running on into the index
+        let mut f = tempfile::tempfile().unwrap();
+        append_to(&mut f, &WriterConfig::default(), &[("abc", "def")]);
+        f.seek(SeekFrom::Start(2048 + 4)).unwrap();
+        f.write_all(&[20, 0, 0, 0]).unwrap();
+        let before = contents(&mut f);
+
+        let err = Writer::append(f.try_clone().unwrap(), &WriterConfig::default()).err().unwrap();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::RecordOverrun { .. }) => (),
+            _ => panic!("expected RecordOverrun, got: {:?}", err),
+        }
+        assert!(contents(&mut f) == before);
+    }
+
+    #[test]
+    fn appending_keeps_the_files_compression() {
+        let long = "pink and red, ".repeat(20);
+        let kvs = [("abc", &long[..]), ("q", "burp")];
+        let get = |data: &[u8], key: &[u8]| {
+            let mut buf = vec![0u8; 1000];
+            let n = Reader::new(data).unwrap().get(key, &mut buf).unwrap().unwrap();
+            buf.truncate(n);
+            buf
+        };
+
+        let mut f = tempfile::tempfile().unwrap();
+        append_to(&mut f, WriterConfig::default().compression(Compression::Lz4), &kvs[..1]);
+        // new values go in uncompressed, but still tagged
+        let summary = append_to(&mut f, &WriterConfig::default(), &kvs[1..]);
+        assert_eq!(summary.stats.compressed, 1);
+        assert_eq!(summary.stats.value_sizes.max, long.len() as u64);
+
+        let data = contents(&mut f);
+        assert!(Reader::new(&data).unwrap().is_compressed());
+        assert!(verify::verify(&Reader::new(&data).unwrap()).is_ok());
+        assert_eq!(get(&data, b"abc"), long.as_bytes());
+        assert_eq!(get(&data, b"q"), b"burp");
+
+        let mut f = tempfile::tempfile().unwrap();
+        append_to(&mut f, &WriterConfig::default(), &kvs[1..]);
+        let config = WriterConfig::default().compression(Compression::Lz4).clone();
+        let summary = append_to(&mut f, &config, &kvs[..1]);
+        assert_eq!(summary.stats.compressed, 0);
+
+        let data = contents(&mut f);
+        assert!(!Reader::new(&data).unwrap().is_compressed());
+        assert_eq!(values(&data, b"abc"), vec![long.as_bytes().to_vec()]);
+    }
+
+    fn user(n: usize) -> String {
+        format!(r#"{{"id": {}, "name": "user{}", "color": "red"}}"#, n, n * 7)
+    }
+
+    fn write_users(config: &WriterConfig, users: usize) -> (BuildSummary, Vec<u8>) {
+        let mut cur = Cursor::new(Vec::new());
+        let summary = {
+            let mut w = Writer::with_config(&mut cur, config).unwrap();
+            for n in 0..users {
+                w.put(format!("key{}", n).as_bytes(), user(n).as_bytes()).unwrap();
+            }
+            w.finish().unwrap()
+        };
+        (summary, cur.into_inner())
+    }
+
+    #[test]
+    fn dictionary_is_trained_on_the_values() {
+        let mut config = WriterConfig::default();
+        config.min_compressed_size(0).compression(Compression::Zstd { level: 3 });
+        let (_, plain) = write_users(&config, 2000);
+
+        config.compression(Compression::ZstdDictionary { level: 3, size: 4096 });
+        let (summary, data) = write_users(&config, 2000);
+        assert!(data.len() < plain.len());
+        assert_eq!(summary.stats.compressed, 2000);
+
+        let (start, trailer) = Trailer::find(&data).unwrap().unwrap();
+        let dictionary = summary.dictionary.clone().unwrap();
+        assert_eq!(dictionary.start, summary.index.end);
+        assert_eq!(dictionary.end, start as u64);
+        assert_eq!(trailer.dictionary(), Some(dictionary.start as usize..start));
+
+        let cdb = Reader::new(&data).unwrap();
+        assert!(verify::verify(&cdb).is_ok());
+        let handle = cdb_handle::new(data.clone().into_boxed_slice()).unwrap();
+        let mut buf = vec![0u8; 100];
+        for n in (0..2000).step_by(97) {
+            let (key, value) = (format!("key{}", n), user(n));
+            let len = cdb.get(key.as_bytes(), &mut buf).unwrap().unwrap();
+            assert_eq!(&buf[..len], value.as_bytes());
+            let bytes = handle.get_bytes(key.as_bytes()).unwrap().unwrap();
+            assert_eq!(&bytes[..], value.as_bytes());
+        }
+    }
+
+    #[test]
+    fn too_few_values_to_train_on_are_compressed_on_their_own() {
+        let mut config = WriterConfig::default();
+        config
+            .min_compressed_size(0)
+            .compression(Compression::ZstdDictionary { level: 3, size: 4096 });
+        let (summary, data) = write_users(&config, 3);
+        assert_eq!(summary.dictionary, None);
+
+        let cdb = Reader::new(&data).unwrap();
+        assert!(cdb.is_compressed());
+        assert_eq!(cdb.dictionary(), None);
+        let mut buf = vec![0u8; 100];
+        let len = cdb.get(b"key1", &mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..len], user(1).as_bytes());
+    }
+
+    #[test]
+    fn appending_keeps_the_dictionary() {
+        let mut config = WriterConfig::default();
+        config.compression(Compression::ZstdDictionary { level: 3, size: 4096 });
+        let (summary, data) = write_users(&config, 2000);
+        assert_eq!(summary.stats.compressed, 2000);
+        let dictionary = {
+            let cdb = Reader::new(&data).unwrap();
+            for kv in cdb.iter().unwrap() {
+                assert_eq!(kv.unwrap().v[0], 3);
+            }
+            cdb.dictionary().unwrap().to_vec()
+        };
+
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(&data).unwrap();
+        let extra = user(5000);
+        let summary = append_to(&mut f, &WriterConfig::default(), &[("extra", &extra[..])]);
+        assert!(summary.dictionary.is_some());
+        assert_eq!(summary.stats.compressed, 2001);
+
+        let data = contents(&mut f);
+        let cdb = Reader::new(&data).unwrap();
+        assert_eq!(cdb.dictionary(), Some(&dictionary[..]));
+        assert!(verify::verify(&cdb).is_ok());
+        let stored = cdb.get_ref(b"extra").unwrap().unwrap().v;
+        assert_eq!(stored[0], 3);
+        assert_eq!(&cdb.value(stored).unwrap()[..], extra.as_bytes());
+    }
+
+    #[test]
+    fn appending_keeps_the_filter() {
+        let mut f = tempfile::tempfile().unwrap();
+        let config = WriterConfig::default().filter(0.001).clone();
+        let first = append_to(&mut f, &config, &[("abc", "def"), ("pink", "red")]);
+        let rate = {
+            let data = contents(&mut f);
+            let filter = Reader::new(&data).unwrap().filter().unwrap().to_vec();
+            let region = first.filter.unwrap();
+            assert_eq!(filter.len() as u64, region.end - region.start);
+            Filter::new(&filter).unwrap().false_positive_rate()
+        };
+
+        let summary = append_to(&mut f, &WriterConfig::default(), &[("q", "burp")]);
+        assert!(summary.filter.is_some());
+
+        let data = contents(&mut f);
+        let cdb = Reader::new(&data).unwrap();
+        assert!(verify::verify(&cdb).is_ok());
+        assert_eq!(Filter::new(cdb.filter().unwrap()).unwrap().false_positive_rate(), rate);
+        for key in &["abc", "pink", "q"] {
+            assert_eq!(cdb.count(key.as_bytes()).unwrap(), 1);
+        }
+    }
+
+    fn keys_in_file_order(data: &[u8]) -> Vec<Vec<u8>> {
+        let cdb = Reader::new(data).unwrap();
+        cdb.iter().unwrap().map(|kv| kv.unwrap().k.to_vec()).collect()
+    }
+
+    #[test]
+    fn bucket_layout_groups_records_by_slot() {
+        let mut config = WriterConfig::default();
+        config
+            .layout(Layout::Bucket)
+            .duplicates(DuplicateKeys::KeepLast)
+            .max_index_memory(1024)
+            .checksum(true);
+        let data = write_many(&config);
+        let cdb = Reader::new(&data).unwrap();
+
+        assert!(verify::verify(&cdb).is_ok());
+        assert_eq!(values(&data, b"key123"), vec![b"123/2".to_vec()]);
+        for n in 0..1000 {
+            let k = format!("key{}", n);
+            assert_eq!(cdb.count(k.as_bytes()).unwrap(), 1);
+        }
+
+        let homes: Vec<(usize, usize)> = keys_in_file_order(&data)
+            .iter()
+            .map(|k| {
+                let hash = CDBHash::new(k);
+                let t = cdb.main_table.table(hash);
+                let bucket = cdb.bucket_at(t).unwrap();
+                (t, cdb.main_table.slot(hash, bucket.num_ents as usize))
+            })
+            .collect();
+        let mut sorted = homes.clone();
+        sorted.sort();
+        assert!(homes == sorted);
+    }
+
+    #[test]
+    fn hint_layout_puts_hot_records_first() {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let config = WriterConfig::default().layout(Layout::Hint).clone();
+            let mut w = Writer::with_config(&mut cur, &config).unwrap();
+            w.put_with_hint(b"lukewarm", b"1", 5).unwrap();
+            w.put(b"cold", b"2").unwrap();
+            w.put_with_hint(b"hot", b"3", 100).unwrap();
+            w.put_with_hint(b"also lukewarm", b"4", 5).unwrap();
+            w.finish().unwrap();
+        }
+        let data = cur.into_inner();
+
+        let keys = keys_in_file_order(&data);
+        let expected: Vec<&[u8]> = vec![b"hot", b"lukewarm", b"also lukewarm", b"cold"];
+        assert_eq!(keys, expected);
+        assert_eq!(values(&data, b"cold"), vec![b"2".to_vec()]);
+        assert!(verify::verify(&Reader::new(&data).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn appending_to_a_hint_layout_keeps_duplicates_in_put_order() {
+        let mut f = tempfile::tempfile().unwrap();
+        let config = WriterConfig::default().layout(Layout::Hint).clone();
+        {
+            let mut w = Writer::append(f.try_clone().unwrap(), &config).unwrap();
+            w.put_with_hint(b"abc", b"one", 1).unwrap();
+            w.put(b"pink", b"red").unwrap();
+            w.put_with_hint(b"abc", b"two", 100).unwrap();
+            w.finish().unwrap();
+        }
+        let data = contents(&mut f);
+        let expected: Vec<&[u8]> = vec![b"abc", b"abc", b"pink"];
+        assert_eq!(keys_in_file_order(&data), expected);
+        assert_eq!(values(&data, b"abc"), vec![b"one".to_vec(), b"two".to_vec()]);
+
+        append_to(&mut f, &config, &[("abc", "three")]);
+        let data = contents(&mut f);
+        let cdb = Reader::new(&data).unwrap();
+        assert!(verify::verify(&cdb).is_ok());
+        assert_eq!(cdb.get_ref(b"abc").unwrap().unwrap().v, b"one");
+        assert_eq!(
+            values(&data, b"abc"),
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+    }
+
+    #[test]
+    fn unfinished_files_are_invalid() {
+        let mut cur = Cursor::new(Vec::new());
+        {
+            let mut w = Writer::new(&mut cur).unwrap();
+            w.put(b"abc", b"def").unwrap();
+        }
+        let data = cur.into_inner();
+
+        let cdb = Reader::new(&data).unwrap();
+        assert!(cdb.get_ref(b"abc").is_err());
+        assert!(cdb.iter().is_err());
+        assert!(!verify::verify(&cdb).is_ok());
+    }
+
+    // fails every write once 'limit' bytes have been written
+    struct Full {
+        inner: Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.inner.position() + buf.len() as u64 > self.limit {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Full {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn finish_reports_io_errors() {
+        let mut f = Full { inner: Cursor::new(Vec::new()), limit: 3000 };
+        {
+            let mut w = Writer::new(&mut f).unwrap();
+            w.put(b"abc", b"def").unwrap();
+            assert!(w.finish().is_err());
+        }
+
+        let data = f.inner.into_inner();
+        assert!(Reader::new(&data).unwrap().get_ref(b"abc").is_err());
+    }
+
+    // a file that claims to be ~4 GiB in, without having to write that much
+    struct FarAlong {
+        pos: u64,
+    }
+
+    const FAR_ALONG: u64 = (1 << 32) - 4096;
+
+    impl Write for FarAlong {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pos += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FarAlong {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(n) => FAR_ALONG + n,
+                SeekFrom::Current(n) | SeekFrom::End(n) => (self.pos as i64 + n) as u64,
+            };
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    fn writing_past_4gib_is_an_error_for_32_bit_files() {
+        let value = vec![0u8; 1500];
+
+        let mut w = Writer::new(FarAlong { pos: 0 }).unwrap();
+        w.put(b"fits", &value).unwrap();
+
+        let err = w.put(b"doesn't", &value).unwrap_err();
+        match err.downcast_ref::<CDBError>() {
+            Some(CDBError::FileTooLarge { max_size, size }) => {
+                assert_eq!(*max_size, u64::from(u32::max_value()));
+                assert!(*size > *max_size);
+            }
+            _ => panic!("expected FileTooLarge, got: {:?}", err),
+        }
+
+        let config = WriterConfig::default().pointer_width(PointerWidth::U64).clone();
+        let mut w = Writer::with_config(FarAlong { pos: 0 }, &config).unwrap();
+        for _ in 0..10 {
+            w.put(b"fits", &value).unwrap();
+        }
+        w.finish().unwrap();
+    }
+}
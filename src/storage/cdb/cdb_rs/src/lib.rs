@@ -9,12 +9,23 @@ extern crate failure_derive;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate lz4;
 extern crate memmap;
-
-// dev dependencies
-#[cfg(test)]
+extern crate rand;
+extern crate rayon;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate tempfile;
+extern crate twox_hash;
+extern crate zstd;
+extern crate zstd_safe;
 
 pub mod cdb;
-pub use cdb::{cdb_handle, CDBError, Reader, Result, Writer};
+pub use cdb::{
+    cdb_handle, AtomicWriter, BuildStats, BuildSummary, CDBError, Compression, DuplicateKeys,
+    HashFn, Layout, MainTable, PointerWidth, Reader, Result, SizeStats, StreamWriter, Writer,
+    WriterConfig,
+};
 pub use memmap::Mmap;
@@ -1,50 +1,28 @@
 
-use std::env;
 use std::ops::Range;
 use std::path::PathBuf;
-use tempfile::NamedTempFile;
-use std::io::{BufWriter};
 
 use cdb_rs::*;
 
 
-fn parent_dir(pb: &PathBuf) -> Result<PathBuf> {
-    if pb.is_relative() {
-        let mut cwd = env::current_dir()?;
-        cwd.push(pb);
-        return parent_dir(&cwd);
-    }
-
-    Ok(pb.parent().unwrap().to_path_buf())
-}
-
 const ASCII: Range<u8> = 32u8..127u8;
 
 pub fn create(path: &PathBuf) -> Result<()> {
-    let mut bw = BufWriter::new(
-        NamedTempFile::new_in(parent_dir(path)?)?);
+    let mut w = AtomicWriter::create(path)?;
 
-    {
-        let mut w = Writer::new(&mut bw)?;
+    for x in ASCII {
+        let k = [x];
+        let v = [x];
+        w.put(&k, &v)?;
 
-        for x in ASCII {
-            let k = [x];
-            let v = [x];
+        for y in ASCII {
+            let k = [x, y];
+            let v = [x, y];
             w.put(&k, &v)?;
-
-            for y in ASCII {
-                let k = [x, y];
-                let v = [x, y];
-                w.put(&k, &v)?;
-            }
         }
     }
 
-    let tf = bw.into_inner()?;
-    tf.as_file().sync_all()?;
-    tf.persist(path)
-        .map(|_| ())
-        .map_err(|e| e.into())
+    w.finish().map(|_| ())
 }
 
 
@@ -73,7 +51,7 @@ mod test {
             b.into_boxed_slice()
         };
 
-        let reader = Reader::new(&data);
+        let reader = Reader::new(&data).unwrap();
 
         for x in ASCII {
             let mut buf = vec![0u8; 2];